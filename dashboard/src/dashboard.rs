@@ -2,6 +2,8 @@
 
 use eframe::{egui, CreationContext};
 use local_ip_address::local_ip;
+use std::fs::File;
+use std::io::BufWriter;
 use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
@@ -10,16 +12,29 @@ use std::{thread, time};
 use tokio::runtime;
 use tokio::sync::mpsc;
 
+use logger::binlog::{self, Backend, BinLogWriter};
+use logger::laps::{motion, LapRecord};
+use logger::motion::{MotionConfig, MotionDetector, MotionEvent};
+use logger::retention::RetentionPolicy;
 use logger::Logger;
 use rbmini::connection::RbConnection;
 use rbmini::connection::RbManager;
-use rbmini::message::{decode_rb_message, RbMessage};
-use timer::{Lap, LapType, Session};
+use rbmini::message::{try_decode_rb_message, RbMessage};
+use timer::{
+    Lap, LapType, Motion, PerformanceConfig, PerformanceResult, PerformanceTimer, Session, Track,
+};
 
 use super::http;
 
 const LOG_FILE: &str = "openlaps_logger.db";
 const FRAME_RATE: u64 = 20; // Desired minimum FPS
+const KEEP_RAW_FRAMES: bool = true; // Log the raw frames so they can be decoded again
+const LOG_BACKEND: Backend = Backend::Sqlite; // Where telemetry is logged to
+const BINARY_LOG_FILE: &str = "openlaps_logger.binlog"; // Convert with `main convert`
+const MIN_LAP_TIME: Duration = Duration::from_secs(20); // Anything quicker isn't a real lap
+const STORAGE_CHECK_INTERVAL: u64 = 25 * 60; // Check the disk once a minute (in samples)
+const DELTA_RANGE: f32 = 2.0; // Seconds either side the delta bar covers
+const PERFORMANCE_RESULTS: usize = 4; // Acceleration and braking times shown
 
 macro_rules! send {
     ($ctx:ident, $model:ident, $item:ident, $value:expr) => {
@@ -32,23 +47,21 @@ macro_rules! send {
 struct DashboardModel {
     telemetry: Arc<Mutex<RbMessage>>,
     status: Arc<Mutex<String>>,
+    warning: Arc<Mutex<String>>,
     session: Arc<Mutex<Session>>,
-    lap: Arc<Mutex<Lap>>, // The current lap
-    session_id: u64,
+    lap: Arc<Mutex<Lap>>,                            // The current lap
+    performance: Arc<Mutex<Vec<PerformanceResult>>>, // Latest first
 }
 
 impl DashboardModel {
     fn new() -> Self {
-        let track = timer::Track::new("Default Track".to_string(), (1.0, 1.0), (2.0, 2.0));
         DashboardModel {
             telemetry: Arc::new(Mutex::new(RbMessage::new())),
             status: Arc::new(Mutex::new(String::new())),
-            session: Arc::new(Mutex::new(timer::Session::new(track))),
+            warning: Arc::new(Mutex::new(String::new())),
+            session: Arc::new(Mutex::new(timer::Session::new(track()))),
             lap: Arc::new(Mutex::new(timer::Lap::new(LapType::Out))),
-            session_id: time::SystemTime::now()
-                .duration_since(time::UNIX_EPOCH)
-                .expect("bad times")
-                .as_secs(),
+            performance: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
@@ -56,13 +69,20 @@ impl DashboardModel {
         DashboardModel {
             telemetry: Arc::clone(&self.telemetry),
             status: Arc::clone(&self.status),
+            warning: Arc::clone(&self.warning),
             session: Arc::clone(&self.session),
             lap: Arc::clone(&self.lap),
-            session_id: self.session_id,
+            performance: Arc::clone(&self.performance),
         }
     }
 }
 
+fn track() -> Track {
+    let mut track = Track::new("Default Track".to_string(), (1.0, 1.0), (2.0, 2.0));
+    track.set_min_lap_time(MIN_LAP_TIME);
+    track
+}
+
 struct DashboardApp {
     _rt: runtime::Runtime,
     model: DashboardModel,
@@ -110,7 +130,9 @@ impl eframe::App for DashboardApp {
         let lap = self.model.lap.lock().unwrap();
         let t = self.model.telemetry.lock().unwrap();
         let status = self.model.status.lock().unwrap();
+        let warning = self.model.warning.lock().unwrap();
         let session = self.model.session.lock().unwrap();
+        let performance = self.model.performance.lock().unwrap();
 
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.heading("Openlaps Dashboard");
@@ -126,6 +148,95 @@ impl eframe::App for DashboardApp {
 
             ui.label(egui::RichText::new(pretty_duration(lap.time())).size(224.0));
 
+            // Delta to the reference lap, green when we're up on it
+            if let Some(delta) = session.delta(&lap) {
+                ui.horizontal(|ui| {
+                    delta_bar(ui, delta as f32);
+                    let color = delta_color(delta as f32);
+                    ui.label(
+                        egui::RichText::new(format!("{:+.2}", delta))
+                            .color(color)
+                            .size(48.0),
+                    );
+                    if let Some(predicted) = session.predicted_lap_time(&lap) {
+                        ui.add_space(40.0);
+                        ui.label(egui::RichText::new(pretty_duration(predicted)).size(48.0));
+                    }
+                });
+            }
+
+            // Splits so far this lap against the best for each sector
+            let best = session.best_sectors();
+            if !best.is_empty() {
+                ui.horizontal(|ui| {
+                    for (i, best) in best.iter().enumerate() {
+                        let time = match lap.sectors().get(i) {
+                            Some(time) => *time,
+                            None if i == lap.current_sector() => lap.sector_time(),
+                            None => Duration::ZERO,
+                        };
+                        let color = match (lap.sectors().get(i), best) {
+                            (Some(time), Some(best)) if time <= best => egui::Color32::GREEN,
+                            (Some(_), Some(_)) => egui::Color32::YELLOW,
+                            _ => egui::Color32::WHITE,
+                        };
+                        ui.label(
+                            egui::RichText::new(format!("S{} {}", i + 1, pretty_duration(time)))
+                                .color(color)
+                                .size(48.0),
+                        );
+                        ui.add_space(40.0);
+                    }
+                });
+            }
+
+            // Best lap and what the best sectors add up to
+            if let Some(best) = session.best_lap() {
+                ui.horizontal(|ui| {
+                    ui.label(
+                        egui::RichText::new(format!("Best {}", pretty_duration(best.duration())))
+                            .size(32.0),
+                    );
+                    if let Some(last_lap) = session.last_lap() {
+                        let metrics = last_lap.metrics();
+                        if let (Some(top), Some(g)) = (metrics.max_speed, metrics.max_combined_g) {
+                            ui.add_space(40.0);
+                            ui.label(
+                                egui::RichText::new(format!("Top {:.0} kph {:.1}g", top, g))
+                                    .size(32.0),
+                            );
+                        }
+                    }
+                    if let Some(theoretical) = session.theoretical_best() {
+                        ui.add_space(40.0);
+                        ui.label(
+                            egui::RichText::new(format!(
+                                "Theoretical {}",
+                                pretty_duration(theoretical)
+                            ))
+                            .size(32.0),
+                        );
+                    }
+                });
+            }
+
+            // Acceleration and braking times
+            if !performance.is_empty() {
+                ui.horizontal(|ui| {
+                    for result in performance.iter() {
+                        ui.label(
+                            egui::RichText::new(format!(
+                                "{} {:.2}s",
+                                result.name,
+                                result.time.as_secs_f64()
+                            ))
+                            .size(24.0),
+                        );
+                        ui.add_space(20.0);
+                    }
+                });
+            }
+
             ui.label(format!("GPS Coordinates: {}", t.gps_coordinates()));
             ui.label(format!("GPS Fix: {}", t.is_valid_fix()));
             ui.horizontal(|ui| {
@@ -135,6 +246,13 @@ impl eframe::App for DashboardApp {
                 };
                 ui.label(format!("{}", status));
             });
+            if !warning.is_empty() {
+                ui.label(
+                    egui::RichText::new(format!("{}", warning))
+                        .color(egui::Color32::RED)
+                        .size(32.0),
+                );
+            }
         });
     }
 }
@@ -190,7 +308,57 @@ async fn updater(ctx: eframe::egui::Context, model: DashboardModel) {
     };
 
     // Create a logger to record telemetry to
-    let logger = Logger::new(Path::new(LOG_FILE));
+    let mut logger = Logger::new(Path::new(LOG_FILE));
+    logger.set_keep_raw_frames(KEEP_RAW_FRAMES);
+
+    // Recovery works the laps out from the telemetry, so anything only in
+    // the binary log has to be in the database first. Once it is the log
+    // starts over, or it would grow forever and be read every time.
+    if LOG_BACKEND == Backend::Binary
+        && Path::new(BINARY_LOG_FILE).exists()
+        && logger
+            .import_binlog(BINARY_LOG_FILE)
+            .and_then(|_| binlog::clear(BINARY_LOG_FILE).map_err(|e| e.into()))
+            .is_err()
+    {
+        send!(
+            ctx,
+            model,
+            warning,
+            String::from("Failed to convert the binary log")
+        );
+    }
+
+    // Close off anything cut short by losing power last time
+    if logger.recover(Some(&track())).is_err() {
+        send!(
+            ctx,
+            model,
+            warning,
+            String::from("Failed to recover the last session")
+        );
+    }
+
+    // Make room before we start logging
+    let retention = RetentionPolicy::default();
+    if logger.prune(&retention, time::SystemTime::now()).is_err() {
+        send!(
+            ctx,
+            model,
+            warning,
+            String::from("Failed to prune old sessions")
+        );
+    }
+
+    // The binary log only takes the frames, laps stay in the database
+    let binlog = match LOG_BACKEND {
+        Backend::Sqlite => None,
+        Backend::Binary => match BinLogWriter::open(BINARY_LOG_FILE) {
+            Err(e) => panic!("Failed to open binary log: {}", e),
+            Ok(binlog) => Some(binlog),
+        },
+    };
+    let mut recorder = Recorder::new(binlog);
 
     // Start another thread to stream from the racebox mini
     let (tx, mut rx) = mpsc::channel(32);
@@ -209,40 +377,355 @@ async fn updater(ctx: eframe::egui::Context, model: DashboardModel) {
 
     send!(ctx, model, status, String::from("Waiting for GPS fix"));
     while let Some(msg) = rx.recv().await {
-        let rb_msg = decode_rb_message(&msg.value);
-
-        if rb_msg.is_valid_fix() {
+        if try_decode_rb_message(&msg.value).is_ok_and(|rb_msg| rb_msg.is_valid_fix()) {
             break;
         }
     }
 
-    send!(ctx, model, status, String::from("Running"));
+    send!(ctx, model, status, String::from("Waiting to move"));
+    let mut detector = MotionDetector::new(MotionConfig::default());
+    let mut performance = PerformanceTimer::new(PerformanceConfig::default());
+    let mut session_id: Option<u64> = None;
+    let mut laps_written = 0; // Laps in the session already in the database
+    let mut samples: u64 = 0;
     while let Some(msg) = rx.recv().await {
-        let rb_msg = decode_rb_message(&msg.value);
+        if samples.is_multiple_of(STORAGE_CHECK_INTERVAL) {
+            let warning = logger.storage_warning(&retention).unwrap_or_default();
+            send!(ctx, model, warning, warning);
+        }
+        samples += 1;
 
-        // TODO check to see if:
-        // 1. Check to see if we're already logging, if so, keeping going
-        // 2. Otherwise, check to see if we're going faster than 5mph, start logging
-        // 3. Finally, check to see if we've stopped for more than 2 minutes, stop logging
+        let received_at = time::SystemTime::now();
 
-        if logger.write(model.session_id, &rb_msg.to_json()).is_err() {
-            continue; // do nothing for now
+        // Keep the raw frame even if it turns out to be bad
+        if let Err(e) = recorder.frame(&logger, &msg.value, received_at) {
+            send!(ctx, model, warning, e);
         }
 
-        let mut lap = lap_mutex.lock().unwrap();
+        let rb_msg = match try_decode_rb_message(&msg.value) {
+            Err(e) => {
+                send!(ctx, model, warning, e);
+                continue;
+            }
+            Ok(rb_msg) => rb_msg,
+        };
+
+        // Time laps by the GPS clock, the same as replaying the log later
+        let at = rb_msg
+            .utc()
+            .map(time::SystemTime::from)
+            .unwrap_or(received_at);
+        let motion = motion(&rb_msg);
         let coords = rb_msg.gps_coordinates();
-        lap.add_point(coords.latitude(), coords.longitude());
+        let fix = Fix {
+            lat: coords.latitude(),
+            long: coords.longitude(),
+            at,
+            motion,
+        };
+        if let Err(e) = recorder.sample(&logger, &rb_msg, fix) {
+            send!(ctx, model, warning, e);
+        }
 
-        let mut session = session_mutex.lock().unwrap();
-        if session.is_lap_complete(&lap.copy()) {
-            *lap = session.add_lap(lap.copy()); // Save the lap and get the next lap
+        // Sessions start when we get moving and end once we've been stopped a while
+        match detector.update(rb_msg.speed(), received_at) {
+            Some(MotionEvent::Start(at)) => {
+                // Session ids are the start time in seconds
+                let id = at
+                    .duration_since(time::UNIX_EPOCH)
+                    .expect("bad times")
+                    .as_secs();
+                if logger.start_session(id, at).is_err() {
+                    send!(ctx, model, warning, String::from("Failed to start session"));
+                }
+                // Everything since we started moving is part of the session
+                if let Err(e) = recorder.start(&logger, id) {
+                    send!(ctx, model, warning, e);
+                }
+                let mut lap = lap_mutex.lock().unwrap();
+                let mut session = session_mutex.lock().unwrap();
+                *session = Session::new(track());
+                *lap = session.start();
+                session_id = Some(id);
+                laps_written = 0;
+                send!(ctx, model, status, String::from("Running"));
+            }
+            Some(MotionEvent::Stop(at)) => {
+                let logged = recorder.take_logged();
+                // Nothing after we stopped is part of the session
+                if let Err(e) = recorder.stop() {
+                    send!(ctx, model, warning, e);
+                }
+                if let Some(id) = session_id.take() {
+                    // Whatever lap we were on is the in lap. The last sample
+                    // logged is from when we stopped, so that's where it ends.
+                    let mut lap = lap_mutex.lock().unwrap();
+                    let mut session = session_mutex.lock().unwrap();
+                    time_laps(&mut session, &mut lap, logged);
+                    session.finish(lap.copy());
+                    *lap = session.start();
+                    write_laps(&logger, id, &session, &mut laps_written);
+                    if logger.end_session(id, at).is_err() {
+                        send!(ctx, model, warning, String::from("Failed to end session"));
+                    }
+                }
+                send!(ctx, model, status, String::from("Waiting to move"));
+            }
+            None => {}
+        }
+        if let Err(e) = recorder.settle(&logger, detector.is_pending()) {
+            send!(ctx, model, warning, e);
         }
 
+        // Launches happen before the session starts, so this always runs
+        for result in performance.update(motion.speed, motion.longitudinal_g, at) {
+            if let Some(id) = session_id {
+                if logger.write_performance(id, &result).is_err() {
+                    // do nothing for now
+                }
+            }
+            let mut results = model.performance.lock().unwrap();
+            results.insert(0, result);
+            results.truncate(PERFORMANCE_RESULTS);
+        }
+
+        let session_id = match session_id {
+            Some(id) => id,
+            None => {
+                send!(ctx, model, telemetry, rb_msg);
+                continue;
+            }
+        };
+
+        // The timer only sees the samples that made it into the log, which
+        // lag behind while the detector makes its mind up
+        let mut lap = lap_mutex.lock().unwrap();
+        let mut session = session_mutex.lock().unwrap();
+        time_laps(&mut session, &mut lap, recorder.take_logged());
+        write_laps(&logger, session_id, &session, &mut laps_written);
+
         send!(ctx, model, telemetry, rb_msg);
     }
     // XXX we don't have a decent way to shut down!
 }
 
+// What the timer needs from a sample
+struct Fix {
+    lat: f64,
+    long: f64,
+    at: time::SystemTime,
+    motion: Motion,
+}
+
+// A frame on its way to the log
+struct Held {
+    frame: Vec<u8>,
+    received_at: time::SystemTime,
+    json: Option<String>, // None if the frame couldn't be decoded
+    fix: Option<Fix>,
+}
+
+// Logs the frames and samples of the current session. While the motion
+// detector makes its mind up about starting or stopping they're held back,
+// then logged if they turn out to be part of the session or dropped if not,
+// so a session's samples match its start and end.
+struct Recorder {
+    binlog: Option<BinLogWriter<BufWriter<File>>>, // Frames go here instead of the database
+    session_id: Option<u64>,
+    holding: bool,
+    held: Vec<Held>,
+    logged: Vec<Fix>, // Samples logged that the timer hasn't seen yet
+}
+
+impl Recorder {
+    fn new(binlog: Option<BinLogWriter<BufWriter<File>>>) -> Self {
+        Recorder {
+            binlog,
+            session_id: None,
+            holding: true,
+            held: Vec::new(),
+            logged: Vec::new(),
+        }
+    }
+
+    fn frame(
+        &mut self,
+        logger: &Logger,
+        frame: &[u8],
+        received_at: time::SystemTime,
+    ) -> Result<(), String> {
+        if self.holding {
+            self.held.push(Held {
+                frame: frame.to_vec(),
+                received_at,
+                json: None,
+                fix: None,
+            });
+            return Ok(());
+        }
+        match (self.session_id, self.binlog.as_mut()) {
+            (None, _) => Ok(()),
+            (Some(_), Some(binlog)) => binlog
+                .write_frame(frame, received_at)
+                .map_err(|e| format!("Failed to log frame: {}", e)),
+            (Some(id), None) => logger
+                .write_raw(id, frame, received_at)
+                .map_err(|e| format!("Failed to log raw frame: {}", e)),
+        }
+    }
+
+    // The last frame, decoded
+    fn sample(&mut self, logger: &Logger, msg: &RbMessage, fix: Fix) -> Result<(), String> {
+        if self.holding {
+            if let Some(held) = self.held.last_mut() {
+                held.json = Some(msg.to_json());
+                held.fix = Some(fix);
+            }
+            return Ok(());
+        }
+        if self.session_id.is_some() {
+            self.logged.push(fix);
+        }
+        self.write(logger, &msg.to_json())
+    }
+
+    // Samples logged since last time, in order, for the timer
+    fn take_logged(&mut self) -> Vec<Fix> {
+        std::mem::take(&mut self.logged)
+    }
+
+    // The binary log only takes the frames
+    fn write(&self, logger: &Logger, json: &str) -> Result<(), String> {
+        match (self.session_id, &self.binlog) {
+            (Some(id), None) => logger
+                .write(id, json)
+                .map_err(|e| format!("Failed to log sample: {}", e)),
+            _ => Ok(()),
+        }
+    }
+
+    // Log everything held since we started moving under the new session
+    fn start(&mut self, logger: &Logger, session_id: u64) -> Result<(), String> {
+        if let Some(binlog) = self.binlog.as_mut() {
+            if let Err(e) = binlog.start_session(session_id) {
+                panic!("Failed to start binary log session: {}", e);
+            }
+        }
+        self.session_id = Some(session_id);
+        self.release(logger)?;
+        self.sync()
+    }
+
+    // Anything held is from after the session stopped
+    fn stop(&mut self) -> Result<(), String> {
+        self.session_id = None;
+        self.holding = true;
+        self.held.clear();
+        self.logged.clear();
+        self.sync()
+    }
+
+    // The binary log only flushes every so often by itself, make sure the
+    // start and end of a session survive losing power
+    fn sync(&mut self) -> Result<(), String> {
+        match self.binlog.as_mut() {
+            Some(binlog) => binlog
+                .sync()
+                .map_err(|e| format!("Failed to sync binary log: {}", e)),
+            None => Ok(()),
+        }
+    }
+
+    // Call after every detector update, pending is whether it's waiting to
+    // see if we've really started or stopped
+    fn settle(&mut self, logger: &Logger, pending: bool) -> Result<(), String> {
+        match (self.session_id, pending) {
+            (_, true) => {
+                self.holding = true;
+                Ok(())
+            }
+            // Not moving, nothing to keep
+            (None, false) => {
+                self.held.clear();
+                Ok(())
+            }
+            // Got going again before the session stopped
+            (Some(_), false) => self.release(logger),
+        }
+    }
+
+    fn release(&mut self, logger: &Logger) -> Result<(), String> {
+        self.holding = false;
+        let mut result = Ok(());
+        for held in std::mem::take(&mut self.held) {
+            self.logged.extend(held.fix);
+            let written =
+                self.frame(logger, &held.frame, held.received_at)
+                    .and_then(|_| match &held.json {
+                        Some(json) => self.write(logger, json),
+                        None => Ok(()),
+                    });
+            // The first problem is enough to go on
+            if result.is_ok() {
+                result = written;
+            }
+        }
+        result
+    }
+}
+
+// Run samples through the timer, saving the lap and starting the next one
+// at start/finish
+fn time_laps(session: &mut Session, lap: &mut Lap, fixes: Vec<Fix>) {
+    for fix in fixes {
+        lap.add_sample(fix.lat, fix.long, fix.at, fix.motion);
+        session.update_pits(lap);
+        session.split(lap);
+        if session.is_lap_complete(lap) {
+            *lap = session.add_lap(lap.copy());
+        }
+    }
+}
+
+// Save any laps finished since last time, at start/finish or in the pits
+fn write_laps(logger: &Logger, session_id: u64, session: &Session, written: &mut usize) {
+    for lap in &session.laps()[*written..] {
+        let record = LapRecord {
+            session_id,
+            lap_type: *lap.number(),
+            start: lap.start_time().unwrap_or(time::UNIX_EPOCH),
+            end: lap.end_time().unwrap_or(time::UNIX_EPOCH),
+            lap_time: lap.duration(),
+            sectors: lap.sectors().to_vec(),
+            valid: lap.is_valid(),
+        };
+        if logger.write_lap(&record).is_err() {
+            // do nothing for now
+        }
+    }
+    *written = session.laps().len();
+}
+
+fn delta_color(delta: f32) -> egui::Color32 {
+    if delta <= 0.0 {
+        egui::Color32::GREEN
+    } else {
+        egui::Color32::RED
+    }
+}
+
+// Grows left from the centre when ahead and right when behind
+fn delta_bar(ui: &mut egui::Ui, delta: f32) {
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(400.0, 48.0), egui::Sense::hover());
+    let painter = ui.painter();
+    painter.rect_filled(rect, 0.0, egui::Color32::DARK_GRAY);
+    let centre = rect.center().x;
+    let end = centre + (delta / DELTA_RANGE).clamp(-1.0, 1.0) * rect.width() / 2.0;
+    let bar = egui::Rect::from_x_y_ranges(centre.min(end)..=centre.max(end), rect.y_range());
+    painter.rect_filled(bar, 0.0, delta_color(delta));
+    painter.vline(centre, rect.y_range(), (2.0, egui::Color32::WHITE));
+}
+
 fn pretty_duration(duration: Duration) -> String {
     let tenths = duration.subsec_millis() / 100;
     let sec = duration.as_secs() % 60;
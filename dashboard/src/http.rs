@@ -1,22 +1,95 @@
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
-use hyper::{Body, Request, Response};
+use hyper::{Body, Request, Response, StatusCode};
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::runtime::Handle;
+use tokio::task;
 
+use logger::query::{Decimation, SessionQuery};
 use logger::Logger;
 
 const LOG_FILE: &str = "/tmp/openlaps_dashboard_testing.db";
 
-pub async fn handle(_: Request<Body>) -> Result<Response<Body>, Infallible> {
+pub async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
     // Create a logger to record telemetry to
     // XXX this is only temporary, needs to be passed as part of context
     let logger = Logger::new(Path::new(LOG_FILE));
+
+    if let Some(session_id) = req.uri().path().strip_prefix("/sessions/") {
+        return Ok(session(logger, session_id, req.uri().query()));
+    }
+
     let value = logger.get_last().unwrap();
     Ok(Response::new(value.into()))
 }
 
+// GET /sessions/<id>?from=<ms>&to=<ms>&every=<n>&minmax=<n>
+//
+// Samples of a session as a JSON array. from and to are milliseconds since
+// the epoch, every keeps every nth sample and minmax keeps the slowest and
+// fastest of each n samples. Samples are sent as they're read, so a long
+// session never has to fit in memory.
+fn session(logger: Logger, session_id: &str, params: Option<&str>) -> Response<Body> {
+    let session_id: u64 = match session_id.parse() {
+        Err(_) => return error(StatusCode::NOT_FOUND, "No such session"),
+        Ok(id) => id,
+    };
+
+    let mut query = SessionQuery::default();
+    for param in params.unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        let value: u64 = match value.parse() {
+            Err(_) => return error(StatusCode::BAD_REQUEST, "Bad query parameter"),
+            Ok(v) => v,
+        };
+        match key {
+            "from" => query.from = Some(UNIX_EPOCH + Duration::from_millis(value)),
+            "to" => query.to = Some(UNIX_EPOCH + Duration::from_millis(value)),
+            "every" => query.decimation = Decimation::EveryNth(value as usize),
+            "minmax" => query.decimation = Decimation::MinMax(value as usize),
+            _ => return error(StatusCode::BAD_REQUEST, "Unknown query parameter"),
+        }
+    }
+
+    let (mut sender, body) = Body::channel();
+    let runtime = Handle::current();
+    // Reading the database blocks, keep it off the async threads
+    task::spawn_blocking(move || {
+        let mut send = |chunk: String| runtime.block_on(sender.send_data(chunk.into())).is_ok();
+        if !send("[".to_string()) {
+            return;
+        }
+        for (i, sample) in logger.query_session(session_id, query).enumerate() {
+            let sample = match sample {
+                // Too late for an error status, cut the body short instead
+                Err(_) => return sender.abort(),
+                Ok(sample) => sample,
+            };
+            let separator = if i > 0 { "," } else { "" };
+            if !send(format!("{}{}", separator, sample.to_json())) {
+                return; // The client went away
+            }
+        }
+        send("]".to_string());
+    });
+
+    let mut response = Response::new(body);
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(message.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
 pub async fn start() {
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
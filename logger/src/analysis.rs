@@ -0,0 +1,310 @@
+use std::error::Error;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rbmini::message::RbMessage;
+use timer::Track;
+
+use crate::export::{Channel, Units};
+use crate::replay;
+use crate::Logger;
+
+// The RaceBox Mini streams at 25hz, used if the samples don't tell us otherwise
+const DEFAULT_SAMPLE_RATE: f64 = 25.0;
+
+// CSV layouts understood by third party analysis tools
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Layout {
+    Motec, // MoTeC i2
+    Aim,   // AiM Race Studio
+}
+
+impl Layout {
+    fn format(&self) -> &'static str {
+        match self {
+            Layout::Motec => "MoTeC CSV File",
+            Layout::Aim => "AiM CSV File",
+        }
+    }
+
+    // Name and unit each tool expects for our channels
+    fn channel(&self, channel: Channel) -> (&'static str, &'static str) {
+        match self {
+            Layout::Motec => match channel {
+                Channel::Latitude => ("GPS Latitude", "deg"),
+                Channel::Longitude => ("GPS Longitude", "deg"),
+                Channel::Speed => ("GPS Speed", "km/h"),
+                Channel::Heading => ("GPS Heading", "deg"),
+                Channel::Altitude => ("GPS Altitude", "m"),
+                Channel::Satellites => ("GPS Sats", ""),
+                Channel::HorizontalAccuracy => ("GPS Pos Accuracy", "m"),
+                Channel::GForceX => ("G Force Long", "G"),
+                Channel::GForceY => ("G Force Lat", "G"),
+                Channel::GForceZ => ("G Force Vert", "G"),
+                Channel::RotRateX => ("Roll Rate", "deg/s"),
+                Channel::RotRateY => ("Pitch Rate", "deg/s"),
+                Channel::RotRateZ => ("Yaw Rate", "deg/s"),
+            },
+            Layout::Aim => match channel {
+                Channel::Latitude => ("GPS Latitude", "deg"),
+                Channel::Longitude => ("GPS Longitude", "deg"),
+                Channel::Speed => ("GPS Speed", "km/h"),
+                Channel::Heading => ("GPS Heading", "deg"),
+                Channel::Altitude => ("GPS Altitude", "m"),
+                Channel::Satellites => ("GPS Nsat", "#"),
+                Channel::HorizontalAccuracy => ("GPS PosAccuracy", "m"),
+                Channel::GForceX => ("InlineAcc", "g"),
+                Channel::GForceY => ("LateralAcc", "g"),
+                Channel::GForceZ => ("VerticalAcc", "g"),
+                Channel::RotRateX => ("RollRate", "deg/s"),
+                Channel::RotRateY => ("PitchRate", "deg/s"),
+                Channel::RotRateZ => ("YawRate", "deg/s"),
+            },
+        }
+    }
+
+    fn time_unit(&self) -> &'static str {
+        match self {
+            Layout::Motec => "s",
+            Layout::Aim => "sec",
+        }
+    }
+}
+
+impl Logger {
+    // Write one session out as CSV in a layout MoTeC i2 or Race Studio can
+    // import. The track is needed to find the lap crossings for the beacon.
+    pub fn export_analysis_csv<W: Write>(
+        &self,
+        session_id: u64,
+        layout: Layout,
+        track: Option<&Track>,
+        out: &mut W,
+    ) -> Result<(), Box<dyn Error>> {
+        let samples = self.get_session(session_id)?;
+        write_analysis_csv(session_id, &samples, layout, track, out)
+    }
+}
+
+fn write_analysis_csv<W: Write>(
+    session_id: u64,
+    samples: &[RbMessage],
+    layout: Layout,
+    track: Option<&Track>,
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    let crossings = match track {
+        Some(track) => replay::crossings(samples, track),
+        None => Vec::new(),
+    };
+    let times = elapsed(samples);
+    let duration = times.last().copied().unwrap_or(0.0);
+    let sample_rate = if samples.len() > 1 && duration > 0.0 {
+        (samples.len() - 1) as f64 / duration
+    } else {
+        DEFAULT_SAMPLE_RATE
+    };
+
+    // The beacon fires where the timer crossed the line, between the two
+    // samples either side of it
+    let markers: Vec<f64> = crossings
+        .iter()
+        .map(|crossing| {
+            let before = &samples[crossing.index - 1];
+            let before_at = before.utc().map(SystemTime::from).unwrap_or(UNIX_EPOCH);
+            let since = crossing.at.duration_since(before_at).unwrap_or_default();
+            times[crossing.index - 1] + since.as_secs_f64()
+        })
+        .collect();
+
+    let start = samples.first().and_then(|msg| msg.utc());
+    let (date, time) = match (start, layout) {
+        (Some(utc), Layout::Motec) => (
+            utc.format("%d/%m/%Y").to_string(),
+            utc.format("%H:%M:%S").to_string(),
+        ),
+        (Some(utc), Layout::Aim) => (
+            utc.format("%A, %B %-d, %Y").to_string(),
+            utc.format("%-I:%M %p").to_string(),
+        ),
+        (None, _) => (String::new(), String::new()),
+    };
+
+    let session = format!("Openlaps session {}", session_id);
+    let rate = format!("{:.3}", sample_rate);
+    let length = format!("{:.3}", duration);
+    let marker_times: Vec<String> = markers.iter().map(|t| format!("{:.3}", t)).collect();
+    match layout {
+        Layout::Motec => {
+            write_row(out, &["Format", layout.format()])?;
+            write_row(out, &["Venue", track.map_or("", |t| t.name())])?;
+            write_row(out, &["Vehicle", ""])?;
+            write_row(out, &["Driver", ""])?;
+            write_row(out, &["Device", "RaceBox Mini"])?;
+            write_row(out, &["Comment", &session])?;
+            write_row(out, &["Log Date", &date])?;
+            write_row(out, &["Log Time", &time])?;
+            write_row(out, &["Sample Rate", &rate, "Hz"])?;
+            write_row(out, &["Duration", &length, "s"])?;
+            write_row(out, &["Range", "entire outing"])?;
+        }
+        Layout::Aim => {
+            write_row(out, &["Format", layout.format()])?;
+            write_row(out, &["Session", &session])?;
+            write_row(out, &["Vehicle", ""])?;
+            write_row(out, &["Racer", ""])?;
+            write_row(out, &["Championship", ""])?;
+            write_row(out, &["Comment", track.map_or("", |t| t.name())])?;
+            write_row(out, &["Date", &date])?;
+            write_row(out, &["Time", &time])?;
+            write_row(out, &["Sample Rate", &rate])?;
+            write_row(out, &["Duration", &length])?;
+            write_row(out, &["Segment", "Session"])?;
+        }
+    }
+    let mut row = vec!["Beacon Markers"];
+    row.extend(marker_times.iter().map(|m| m.as_str()));
+    write_row(out, &row)?;
+    writeln!(out)?;
+
+    // Channel names, then units
+    let channels = Channel::all();
+    let mut names = vec!["Time", "Beacon"];
+    let mut units = vec![layout.time_unit(), ""];
+    for channel in channels.iter() {
+        let (name, unit) = layout.channel(*channel);
+        names.push(name);
+        units.push(unit);
+    }
+    write_row(out, &names)?;
+    write_row(out, &units)?;
+    writeln!(out)?;
+
+    // A row of its own for each beacon, at the crossing. Anything but the
+    // position comes from the sample after the line.
+    let mut beacons = crossings.iter().zip(markers.iter()).peekable();
+    for (i, msg) in samples.iter().enumerate() {
+        while let Some((crossing, time)) = beacons.next_if(|(c, _)| c.index == i) {
+            let mut row = vec![format!("{:.3}", time), "1".to_string()];
+            for channel in channels.iter() {
+                row.push(match channel {
+                    Channel::Latitude => format!("{:.7}", crossing.lat),
+                    Channel::Longitude => format!("{:.7}", crossing.long),
+                    _ => channel.value(msg, Units::Metric),
+                });
+            }
+            write_row(out, &row.iter().map(|v| v.as_str()).collect::<Vec<_>>())?;
+        }
+        let mut row = vec![format!("{:.3}", times[i]), "0".to_string()];
+        for channel in channels.iter() {
+            row.push(channel.value(msg, Units::Metric));
+        }
+        let row: Vec<&str> = row.iter().map(|v| v.as_str()).collect();
+        write_row(out, &row)?;
+    }
+    Ok(())
+}
+
+// Both tools want every cell quoted, with any quotes inside doubled
+fn write_row<W: Write>(out: &mut W, cells: &[&str]) -> Result<(), Box<dyn Error>> {
+    let cells: Vec<String> = cells
+        .iter()
+        .map(|c| format!("\"{}\"", c.replace('"', "\"\"")))
+        .collect();
+    writeln!(out, "{}", cells.join(","))?;
+    Ok(())
+}
+
+// Seconds since the first sample. Samples without a usable timestamp are
+// assumed to be one sample period after the one before.
+fn elapsed(samples: &[RbMessage]) -> Vec<f64> {
+    let start = samples.first().and_then(|msg| msg.utc());
+    let mut times: Vec<f64> = Vec::with_capacity(samples.len());
+    for msg in samples {
+        let time = match (start, msg.utc()) {
+            (Some(start), Some(utc)) => (utc - start).num_milliseconds() as f64 / 1000.0,
+            _ => times.last().map_or(0.0, |t| t + 1.0 / DEFAULT_SAMPLE_RATE),
+        };
+        times.push(time);
+    }
+    times
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rbmini::message::Datetime;
+
+    fn samples() -> Vec<RbMessage> {
+        let mut samples = Vec::new();
+        for (i, lat) in [1, 2, 3, 4, 3, 2].iter().enumerate() {
+            let mut msg = RbMessage::new();
+            msg.update_coordinates(50000000, lat * 10000000);
+            let datetime = Datetime {
+                year: 2023,
+                month: 3,
+                day: 4,
+                hour: 10,
+                minute: 0,
+                second: 0,
+            };
+            msg.update_datetime(datetime, i as i32 * 40000000);
+            samples.push(msg);
+        }
+        samples
+    }
+
+    fn export(layout: Layout) -> Vec<String> {
+        let track = Track::new("Test Track".to_string(), (2.5, 0.0), (2.5, 10.0));
+        let mut out = Vec::new();
+        write_analysis_csv(3, &samples(), layout, Some(&track), &mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_motec() {
+        let lines = export(Layout::Motec);
+        assert_eq!(lines[0], r#""Format","MoTeC CSV File""#);
+        assert_eq!(lines[1], r#""Venue","Test Track""#);
+        assert_eq!(lines[6], r#""Log Date","04/03/2023""#);
+        assert_eq!(lines[8], r#""Sample Rate","25.000","Hz""#);
+        assert_eq!(lines[11], r#""Beacon Markers","0.060","0.180""#);
+        assert!(lines[13].starts_with(r#""Time","Beacon","GPS Latitude","GPS Longitude""#));
+        assert!(lines[14].starts_with(r#""s","","deg","deg","km/h""#));
+        assert!(lines[16].starts_with(r#""0.000","0","1.0000000""#));
+        assert!(lines[17].starts_with(r#""0.040","0","2.0000000""#));
+        assert!(lines[18].starts_with(r#""0.060","1","2.5000000","5.0000000""#));
+        assert!(lines[19].starts_with(r#""0.080","0","3.0000000""#));
+        assert_eq!(lines.len(), 24);
+    }
+
+    #[test]
+    fn test_aim() {
+        let lines = export(Layout::Aim);
+        assert_eq!(lines[0], r#""Format","AiM CSV File""#);
+        assert_eq!(lines[6], r#""Date","Saturday, March 4, 2023""#);
+        assert_eq!(lines[7], r#""Time","10:00 AM""#);
+        assert!(lines[13].contains(r#""InlineAcc","LateralAcc","VerticalAcc""#));
+        assert!(lines[14].starts_with(r#""sec","","deg""#));
+    }
+
+    #[test]
+    fn test_elapsed_without_timestamps() {
+        let samples = vec![RbMessage::new(), RbMessage::new(), RbMessage::new()];
+        assert_eq!(elapsed(&samples), vec![0.0, 0.04, 0.08]);
+    }
+
+    #[test]
+    fn test_write_row_quotes() {
+        let mut out: Vec<u8> = Vec::new();
+        write_row(&mut out, &["Session", "Sonoma \"long\""]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\"Session\",\"Sonoma \"\"long\"\"\"\n"
+        );
+    }
+}
@@ -1,19 +1,188 @@
-use std::path::Path;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::process;
+use std::time::{Duration, SystemTime};
 
+use logger::export::CsvOptions;
 use logger::Logger;
-fn main() {
-    println!("Creating logger");
-    let logger = Logger::new(Path::new("/tmp/openlaps_test.db"));
 
-    println!("Logging to {:?}", logger.path());
-    /*if let Err(err) = logger.write("A line of logging") {
-        panic!("{}", err);
-    }*/
+// Look after the sessions in a logger database
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[clap(short, long, default_value_t = String::from("openlaps_logger.db"))]
+    database: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List every session with a summary
+    List,
+    /// Show the laps of a session
+    Laps { session: u64 },
+    /// Print every sample of a session as JSON, one per line
+    Dump { session: u64 },
+    /// Export a session
+    Export {
+        session: u64,
+        #[clap(short, long, value_enum, default_value_t = Format::Csv)]
+        format: Format,
+        /// Write to this file instead of stdout
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+    /// Delete a session and everything logged for it
+    Delete { session: u64 },
+    /// Give a session a name
+    Rename { session: u64, name: String },
+    /// Fold the second session into the first
+    Merge { into: u64, from: u64 },
+    /// Check the database for problems
+    Check,
+    /// Load a binary log into the database
+    Convert { binlog: String },
+    /// Rewrite the database so deleted sessions free up disk space. Older
+    /// databases need this once, it needs as much free space as the database
+    Compact,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum Format {
+    Csv,
+    Gpx,
+}
+
+fn pretty_time(time: Option<SystemTime>) -> String {
+    match time {
+        Some(time) => DateTime::<Utc>::from(time)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        None => "-".to_string(),
+    }
+}
+
+fn pretty_duration(duration: Duration) -> String {
+    let millis = duration.subsec_millis();
+    let sec = duration.as_secs() % 60;
+    let min = duration.as_secs() / 60;
+    format!("{}:{:0>2}.{:0>3}", min, sec, millis)
+}
+
+fn list(logger: &Logger) -> Result<(), Box<dyn Error>> {
+    println!(
+        "{:<12} {:<20} {:<20} {:>8} {:>5} {:>10}  name",
+        "session", "start", "end", "samples", "laps", "best"
+    );
+    for session_id in logger.get_sessions()? {
+        let summary = logger.session_summary(session_id)?;
+        let mut flags = String::new();
+        if summary.starred {
+            flags.push_str(" *");
+        }
+        if summary.recovered {
+            flags.push_str(" (recovered)");
+        }
+        println!(
+            "{:<12} {:<20} {:<20} {:>8} {:>5} {:>10}  {}{}",
+            session_id,
+            pretty_time(summary.start),
+            pretty_time(summary.end),
+            summary.samples,
+            summary.laps,
+            summary.best_lap.map(pretty_duration).unwrap_or_default(),
+            summary.name.unwrap_or_default(),
+            flags
+        );
+    }
+    Ok(())
+}
+
+fn laps(logger: &Logger, session_id: u64) -> Result<(), Box<dyn Error>> {
+    for lap in logger.get_laps(session_id)? {
+        let sectors: Vec<String> = lap.sectors.iter().map(|s| pretty_duration(*s)).collect();
+        println!(
+            "{:<8} {:<20} {:>10} {}{}",
+            format!("{:?}", lap.lap_type),
+            pretty_time(Some(lap.start)),
+            pretty_duration(lap.lap_time),
+            sectors.join(" "),
+            if lap.valid { "" } else { " (invalid)" }
+        );
+    }
+    Ok(())
+}
+
+fn dump(logger: &Logger, session_id: u64) -> Result<(), Box<dyn Error>> {
+    let mut out = BufWriter::new(io::stdout().lock());
+    for sample in logger.query_session(session_id, Default::default()) {
+        writeln!(out, "{}", sample?.to_json())?;
+    }
+    Ok(())
+}
 
-    println!("Closing the log");
-    if let Err(err) = logger.close() {
-        panic!("{}", err);
+fn export(
+    logger: &Logger,
+    session_id: u64,
+    format: Format,
+    output: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    match format {
+        Format::Csv => logger.export_csv(session_id, &CsvOptions::default(), &mut out)?,
+        Format::Gpx => logger.export_gpx(session_id, &mut out)?,
     }
+    out.flush()?;
+    Ok(())
+}
 
-    println!("Done!");
+fn check(logger: &Logger) -> Result<(), Box<dyn Error>> {
+    let problems = logger.check_integrity()?;
+    if problems.is_empty() {
+        println!("ok");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    Err(format!("{} problems found", problems.len()).into())
+}
+
+fn main() {
+    let args = Args::parse();
+    let logger = Logger::new(&args.database);
+
+    let result = match args.command {
+        Command::List => list(&logger),
+        Command::Laps { session } => laps(&logger, session),
+        Command::Dump { session } => dump(&logger, session),
+        Command::Export {
+            session,
+            format,
+            output,
+        } => export(&logger, session, format, output),
+        Command::Delete { session } => logger.delete_session(session).map_err(|e| e.into()),
+        Command::Rename { session, name } => {
+            logger.rename_session(session, &name).map_err(|e| e.into())
+        }
+        Command::Merge { into, from } => logger.merge_sessions(into, from).map_err(|e| e.into()),
+        Command::Check => check(&logger),
+        Command::Convert { binlog } => logger.import_binlog(binlog).map(|rows| {
+            println!("Decoded {} rows", rows);
+        }),
+        Command::Compact => logger.compact().map_err(|e| e.into()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,46 @@
+use std::env;
+use std::process;
+
+use logger::Logger;
+
+// Rebuilds the decoded telemetry from the stored raw frames
+//
+// redecode <database> [session id...]
+//
+// Without any session ids every session with raw frames is decoded again.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: {} <database> [session id...]", args[0]);
+        process::exit(1);
+    }
+
+    let logger = Logger::new(&args[1]);
+    let sessions: Vec<u64> = if args.len() > 2 {
+        args[2..]
+            .iter()
+            .map(|id| match id.parse() {
+                Err(_) => {
+                    eprintln!("Bad session id {}", id);
+                    process::exit(1);
+                }
+                Ok(id) => id,
+            })
+            .collect()
+    } else {
+        match logger.get_raw_sessions() {
+            Err(e) => {
+                eprintln!("Failed to list sessions: {}", e);
+                process::exit(1);
+            }
+            Ok(sessions) => sessions,
+        }
+    };
+
+    for session_id in sessions {
+        match logger.redecode(session_id) {
+            Err(e) => eprintln!("Session {}: {}", session_id, e),
+            Ok(rows) => println!("Session {}: decoded {} rows", session_id, rows),
+        }
+    }
+}
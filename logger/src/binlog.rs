@@ -0,0 +1,599 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use rbmini::message::{rb_checksum, try_decode_rb_message};
+
+use crate::laps::{from_millis, to_millis};
+use crate::Logger;
+
+// An append only log of the raw frames from the device, much lighter on the
+// SD card than a SQLite insert per sample. Convert it into the SQLite schema
+// with Logger::import_binlog once the session is over.
+//
+// The file starts with MAGIC and VERSION, then records of
+//
+//   kind: u8, length: u32 LE, payload: [u8; length], crc32(kind + payload): u32 LE
+//
+// A session record holds the session id. A frame record holds the receive
+// time and the frame, both delta encoded against the previous frame. Every
+// INDEX_INTERVAL frames an index record is written and the delta encoding
+// starts over, so a reader can pick things back up after a damaged record.
+
+const MAGIC: &[u8; 4] = b"OLBL";
+const VERSION: u8 = 1;
+const INDEX_INTERVAL: u64 = 250; // 10 seconds at 25Hz
+
+const SESSION: u8 = 1;
+const FRAME: u8 = 2;
+const INDEX: u8 = 3;
+
+// Record header plus checksum
+const OVERHEAD: usize = 1 + 4 + 4;
+
+// Far bigger than any record we write, anything longer is damage
+const MAX_RECORD: usize = 64 * 1024;
+
+// Which backend the dashboard logs telemetry to
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Backend {
+    Sqlite,
+    Binary,
+}
+
+// Where delta encoding picks up from
+#[derive(Default)]
+struct DeltaState {
+    frame: Vec<u8>,
+    received_at: i64, // ms since the epoch
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+// XOR against the previous frame, then run length encode the zeros, which
+// is most of the frame when little has changed. Written as pairs of
+// (zeros, literal count, literals).
+fn encode_frame(buf: &mut Vec<u8>, frame: &[u8], prev: &[u8]) {
+    let delta: Vec<u8> = frame
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ prev.get(i).unwrap_or(&0))
+        .collect();
+    put_varint(buf, delta.len() as u64);
+    let mut i = 0;
+    while i < delta.len() {
+        let zeros = delta[i..].iter().take_while(|b| **b == 0).count();
+        i += zeros;
+        // A lone zero is cheaper as a literal than starting a new pair
+        let mut end = i;
+        while end < delta.len() && !(delta[end] == 0 && delta.get(end + 1).unwrap_or(&0) == &0) {
+            end += 1;
+        }
+        put_varint(buf, zeros as u64);
+        put_varint(buf, (end - i) as u64);
+        buf.extend_from_slice(&delta[i..end]);
+        i = end;
+    }
+}
+
+fn decode_frame(buf: &[u8], pos: &mut usize, prev: &[u8]) -> Option<Vec<u8>> {
+    let len = get_varint(buf, pos)? as usize;
+    let mut delta = Vec::with_capacity(len);
+    while delta.len() < len {
+        let zeros = get_varint(buf, pos)? as usize;
+        let literals = get_varint(buf, pos)? as usize;
+        if delta.len() + zeros + literals > len {
+            return None;
+        }
+        delta.resize(delta.len() + zeros, 0);
+        delta.extend_from_slice(buf.get(*pos..*pos + literals)?);
+        *pos += literals;
+    }
+    Some(
+        delta
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ prev.get(i).unwrap_or(&0))
+            .collect(),
+    )
+}
+
+pub struct BinLogWriter<W: Write> {
+    out: W,
+    session_id: Option<u64>,
+    frames: u64, // Frames written since the last index
+    state: DeltaState,
+}
+
+impl BinLogWriter<BufWriter<File>> {
+    // Append to the log at path, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut out = BufWriter::new(file);
+        if empty {
+            out.write_all(MAGIC)?;
+            out.write_all(&[VERSION])?;
+        }
+        Ok(BinLogWriter::from_writer(out))
+    }
+}
+
+impl<W: Write> BinLogWriter<W> {
+    // The writer should already have the file header, see write_header
+    pub fn from_writer(out: W) -> Self {
+        BinLogWriter {
+            out,
+            session_id: None,
+            frames: 0,
+            state: DeltaState::default(),
+        }
+    }
+
+    pub fn write_header(&mut self) -> io::Result<()> {
+        self.out.write_all(MAGIC)?;
+        self.out.write_all(&[VERSION])
+    }
+
+    fn write_record(&mut self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&[kind]);
+        hasher.update(payload);
+        self.out.write_all(&[kind])?;
+        self.out.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.out.write_all(payload)?;
+        self.out.write_all(&hasher.finalize().to_le_bytes())
+    }
+
+    pub fn start_session(&mut self, session_id: u64) -> io::Result<()> {
+        self.session_id = Some(session_id);
+        self.frames = 0;
+        self.state = DeltaState::default();
+        self.write_record(SESSION, &session_id.to_le_bytes())
+    }
+
+    fn write_index(&mut self, received_at: i64) -> io::Result<()> {
+        let mut payload = Vec::with_capacity(16);
+        payload.extend_from_slice(&self.session_id.unwrap_or(0).to_le_bytes());
+        payload.extend_from_slice(&received_at.to_le_bytes());
+        self.frames = 0;
+        self.state = DeltaState {
+            frame: Vec::new(),
+            received_at,
+        };
+        self.write_record(INDEX, &payload)
+    }
+
+    pub fn write_frame(&mut self, frame: &[u8], received_at: SystemTime) -> io::Result<()> {
+        if self.session_id.is_none() {
+            return Err(io::Error::other("No session started in the binary log"));
+        }
+        let received_at = to_millis(received_at);
+        if self.frames.is_multiple_of(INDEX_INTERVAL) {
+            // At most one index worth of frames is lost if the power goes
+            self.write_index(received_at)?;
+            self.out.flush()?;
+        }
+
+        let mut payload = Vec::with_capacity(frame.len());
+        put_varint(&mut payload, zigzag(received_at - self.state.received_at));
+        encode_frame(&mut payload, frame, &self.state.frame);
+        self.write_record(FRAME, &payload)?;
+
+        self.frames += 1;
+        self.state = DeltaState {
+            frame: frame.to_vec(),
+            received_at,
+        };
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl BinLogWriter<BufWriter<File>> {
+    // Flush and wait for it to be on the card, not just the OS's cache
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.out.get_ref().sync_data()
+    }
+}
+
+// Empty the log at path once everything in it is in the database. The
+// header stays so a writer can carry on appending.
+pub fn clear<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len((MAGIC.len() + 1) as u64)?;
+    file.sync_all()
+}
+
+// A frame read back out of the log
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub session_id: u64,
+    pub received_at: SystemTime,
+    pub frame: Vec<u8>,
+}
+
+pub struct BinLogReader<R: Read> {
+    input: R,
+    buf: Vec<u8>, // Read from input but not used yet
+    pos: usize,   // Where the next record starts in buf
+    eof: bool,
+    session_id: Option<u64>,
+    state: Option<DeltaState>, // None until we've seen an index
+    damaged: usize,
+}
+
+impl BinLogReader<BufReader<File>> {
+    // Read the log at path a record at a time, it's never all in memory
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        match File::open(path) {
+            Err(e) => Err(format!("Failed to read binary log: {}", e)),
+            Ok(file) => BinLogReader::from_reader(BufReader::new(file)),
+        }
+    }
+}
+
+impl<R: Read> BinLogReader<R> {
+    pub fn from_reader(mut input: R) -> Result<Self, String> {
+        let mut header = [0u8; 5];
+        match input.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err("Not a binary log".to_string())
+            }
+            Err(e) => return Err(format!("Failed to read binary log: {}", e)),
+            Ok(()) => {}
+        }
+        if &header[..4] != MAGIC {
+            return Err("Not a binary log".to_string());
+        }
+        if header[4] != VERSION {
+            return Err(format!("Unsupported binary log version {}", header[4]));
+        }
+        Ok(BinLogReader {
+            input,
+            buf: Vec::new(),
+            pos: 0,
+            eof: false,
+            session_id: None,
+            state: None,
+            damaged: 0,
+        })
+    }
+
+    // Records that were skipped because they were damaged, or couldn't be
+    // decoded because a record before them was
+    pub fn damaged(&self) -> usize {
+        self.damaged
+    }
+
+    // Read ahead until there are at least len bytes from pos on, false if
+    // the log ends first. A read error is treated as the end of the log.
+    fn fill(&mut self, len: usize) -> bool {
+        while self.buf.len() - self.pos < len && !self.eof {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+            let mut chunk = [0u8; 8192];
+            match self.input.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(read) => self.buf.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => self.eof = true,
+            }
+        }
+        self.buf.len() - self.pos >= len
+    }
+
+    // The record at pos if it's all there and the checksum is good
+    fn record(&mut self) -> Option<(u8, Vec<u8>)> {
+        if !self.fill(5) {
+            return None;
+        }
+        let kind = self.buf[self.pos];
+        let len = u32::from_le_bytes(self.buf[self.pos + 1..self.pos + 5].try_into().ok()?);
+        let len = len as usize;
+        // Don't go reading the rest of the log for a damaged length
+        if len > MAX_RECORD || !self.fill(OVERHEAD + len) {
+            return None;
+        }
+        let payload = &self.buf[self.pos + 5..self.pos + 5 + len];
+        let crc = &self.buf[self.pos + 5 + len..self.pos + OVERHEAD + len];
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&[kind]);
+        hasher.update(payload);
+        if hasher.finalize().to_le_bytes() != crc {
+            return None;
+        }
+        Some((kind, payload.to_vec()))
+    }
+
+    // Skip ahead to the next good session or index record
+    fn resync(&mut self) {
+        self.damaged += 1;
+        self.state = None;
+        self.pos += 1;
+        while self.fill(1) {
+            if let Some((SESSION | INDEX, _)) = self.record() {
+                return;
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+impl<R: Read> Iterator for BinLogReader<R> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        while self.fill(1) {
+            let (kind, payload) = match self.record() {
+                Some(record) => record,
+                None => {
+                    self.resync();
+                    continue;
+                }
+            };
+            self.pos += OVERHEAD + payload.len();
+
+            match kind {
+                SESSION if payload.len() == 8 => {
+                    self.session_id = Some(u64::from_le_bytes(payload.try_into().unwrap()));
+                    self.state = None;
+                }
+                INDEX if payload.len() == 16 => {
+                    self.session_id = Some(u64::from_le_bytes(payload[..8].try_into().unwrap()));
+                    self.state = Some(DeltaState {
+                        frame: Vec::new(),
+                        received_at: i64::from_le_bytes(payload[8..].try_into().unwrap()),
+                    });
+                }
+                FRAME => {
+                    let (session_id, state) = match (self.session_id, self.state.as_ref()) {
+                        (Some(session_id), Some(state)) => (session_id, state),
+                        _ => {
+                            self.damaged += 1;
+                            continue;
+                        }
+                    };
+                    let mut pos = 0;
+                    let decoded = get_varint(&payload, &mut pos).and_then(|delta| {
+                        let received_at = state.received_at + unzigzag(delta);
+                        decode_frame(&payload, &mut pos, &state.frame)
+                            .map(|frame| (received_at, frame))
+                    });
+                    let (received_at, frame) = match decoded {
+                        Some(decoded) => decoded,
+                        None => {
+                            self.damaged += 1;
+                            self.state = None;
+                            continue;
+                        }
+                    };
+                    self.state = Some(DeltaState {
+                        frame: frame.clone(),
+                        received_at,
+                    });
+                    return Some(Entry {
+                        session_id,
+                        received_at: from_millis(received_at),
+                        frame,
+                    });
+                }
+                _ => self.damaged += 1, // Something from a newer version
+            }
+        }
+        None
+    }
+}
+
+impl Logger {
+    // Decode every frame in a binary log into the telemetry table. Raw
+    // frames are kept too if keeping raw frames is turned on, frames that
+    // fail their checksum aren't decoded. Sessions that already have
+    // telemetry are skipped, so importing the same log again does nothing.
+    // Returns the number of telemetry rows written.
+    pub fn import_binlog<P: AsRef<Path>>(&self, path: P) -> Result<usize, Box<dyn Error>> {
+        let reader = BinLogReader::open(path)?;
+        let imported = self.get_sessions()?;
+        let tx = self.conn.unchecked_transaction()?;
+        let mut written = 0;
+        for entry in reader {
+            if imported.contains(&entry.session_id) {
+                continue;
+            }
+            self.write_raw(entry.session_id, &entry.frame, entry.received_at)?;
+            if entry.frame.len() <= 4 || !rb_checksum(&entry.frame) {
+                continue;
+            }
+            if let Ok(msg) = try_decode_rb_message(&entry.frame) {
+                self.write(entry.session_id, &msg.to_json())?;
+                written += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const FRAME: [u8; 88] = [
+        0xB5, 0x62, 0xFF, 0x01, 0x50, 0x00, 0xA0, 0xE7, 0x0C, 0x07, 0xE6, 0x07, 0x01, 0x0A, 0x08,
+        0x33, 0x08, 0x37, 0x19, 0x00, 0x00, 0x00, 0x2A, 0xAD, 0x4D, 0x0E, 0x03, 0x01, 0xEA, 0x0B,
+        0xC6, 0x93, 0xE1, 0x0D, 0x3B, 0x37, 0x6F, 0x19, 0x61, 0x8C, 0x09, 0x00, 0x0F, 0x01, 0x09,
+        0x00, 0x9C, 0x03, 0x00, 0x00, 0x2C, 0x07, 0x00, 0x00, 0x23, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xD0, 0x00, 0x00, 0x00, 0x88, 0xA9, 0xDD, 0x00, 0x2C, 0x01, 0x00, 0x59, 0xFD,
+        0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00, 0xFC, 0xFF, 0x06, 0xDB,
+    ];
+
+    // A frame that's a little different each time, like a car moving
+    fn frame(i: usize) -> Vec<u8> {
+        let mut frame = FRAME.to_vec();
+        frame[6] = frame[6].wrapping_add(i as u8);
+        frame[30] = frame[30].wrapping_add((i / 3) as u8);
+        frame
+    }
+
+    fn write_log(sessions: &[u64], frames: usize) -> Vec<u8> {
+        let mut writer = BinLogWriter::from_writer(Vec::new());
+        writer.write_header().unwrap();
+        for session_id in sessions {
+            writer.start_session(*session_id).unwrap();
+            for i in 0..frames {
+                let at = UNIX_EPOCH + Duration::from_millis(1_000_000 + i as u64 * 40);
+                writer.write_frame(&frame(i), at).unwrap();
+            }
+        }
+        writer.out
+    }
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, 127, 128, 300, u64::MAX] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, value);
+            assert_eq!(get_varint(&buf, &mut 0), Some(value));
+        }
+        for value in [0, -1, 1, -40, i64::MIN, i64::MAX] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+    }
+
+    #[test]
+    fn test_frame_delta() {
+        let mut buf = Vec::new();
+        encode_frame(&mut buf, &frame(1), &frame(0));
+        assert!(buf.len() < 20);
+        assert_eq!(decode_frame(&buf, &mut 0, &frame(0)), Some(frame(1)));
+
+        let mut buf = Vec::new();
+        encode_frame(&mut buf, &frame(1), &[]);
+        assert_eq!(decode_frame(&buf, &mut 0, &[]), Some(frame(1)));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = write_log(&[1, 2], 600);
+        // Much smaller than the frames themselves
+        assert!(data.len() < 1200 * FRAME.len() / 3);
+
+        let entries: Vec<Entry> = BinLogReader::from_reader(&data[..]).unwrap().collect();
+        assert_eq!(entries.len(), 1200);
+        assert_eq!(entries[0].session_id, 1);
+        assert_eq!(entries[600].session_id, 2);
+        assert_eq!(entries[599].frame, frame(599));
+        assert_eq!(
+            entries[599].received_at,
+            UNIX_EPOCH + Duration::from_millis(1_000_000 + 599 * 40)
+        );
+    }
+
+    #[test]
+    fn test_damaged_record() {
+        let mut data = write_log(&[1], 600);
+        // Break a frame early on, we lose frames until the next index
+        data[100] ^= 0xFF;
+        let mut reader = BinLogReader::from_reader(&data[..]).unwrap();
+        let entries: Vec<Entry> = reader.by_ref().collect();
+        assert!(reader.damaged() > 0);
+        assert_eq!(entries.len(), 600 - INDEX_INTERVAL as usize);
+        assert_eq!(entries.last().unwrap().frame, frame(599));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut data = write_log(&[1], 10);
+        data.truncate(data.len() - 3);
+        let entries: Vec<Entry> = BinLogReader::from_reader(&data[..]).unwrap().collect();
+        assert_eq!(entries.len(), 9);
+    }
+
+    #[test]
+    fn test_bad_header() {
+        assert!(BinLogReader::from_reader(&b"SQLite format 3"[..]).is_err());
+    }
+
+    #[test]
+    fn test_import_binlog() {
+        // Frames have to pass their checksum to be decoded
+        let mut writer = BinLogWriter::from_writer(Vec::new());
+        writer.write_header().unwrap();
+        writer.start_session(7).unwrap();
+        for _ in 0..30 {
+            writer.write_frame(&FRAME, SystemTime::now()).unwrap();
+        }
+        let mut bad_frame = FRAME;
+        bad_frame[87] = 0xFF;
+        writer.write_frame(&bad_frame, SystemTime::now()).unwrap();
+        let path = std::env::temp_dir().join("openlaps_test_import.binlog");
+        fs::write(&path, writer.out).unwrap();
+        let mut l = Logger::default();
+        l.set_keep_raw_frames(true);
+        assert_eq!(l.import_binlog(&path).unwrap(), 30);
+        // Already imported
+        assert_eq!(l.import_binlog(&path).unwrap(), 0);
+        assert_eq!(l.get_session(7).unwrap().len(), 30);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(l.get_sessions().unwrap(), vec![7]);
+        assert_eq!(l.get_raw_sessions().unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn test_clear() {
+        let path = std::env::temp_dir().join("openlaps_test_clear.binlog");
+        let _ = fs::remove_file(&path);
+        let mut writer = BinLogWriter::open(&path).unwrap();
+        writer.start_session(7).unwrap();
+        writer.write_frame(&FRAME, SystemTime::now()).unwrap();
+        writer.sync().unwrap();
+        let l = Logger::default();
+        assert_eq!(l.import_binlog(&path).unwrap(), 1);
+
+        // Whatever's written after clearing still makes a good log
+        clear(&path).unwrap();
+        assert_eq!(BinLogReader::open(&path).unwrap().count(), 0);
+        writer.start_session(8).unwrap();
+        writer.write_frame(&FRAME, SystemTime::now()).unwrap();
+        writer.sync().unwrap();
+        let entries: Vec<Entry> = BinLogReader::open(&path).unwrap().collect();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].session_id, 8);
+    }
+}
@@ -0,0 +1,310 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::error::Error;
+use std::io::Write;
+
+use rbmini::message::RbMessage;
+use timer::Track;
+
+use crate::replay;
+use crate::Logger;
+
+// Telemetry channels that can be exported
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Channel {
+    Latitude,
+    Longitude,
+    Speed,
+    Heading,
+    Altitude,
+    Satellites,
+    HorizontalAccuracy,
+    GForceX,  // front/back
+    GForceY,  // right/left
+    GForceZ,  // up/down
+    RotRateX, // roll
+    RotRateY, // pitch
+    RotRateZ, // yaw
+}
+
+impl Channel {
+    pub fn all() -> Vec<Channel> {
+        vec![
+            Channel::Latitude,
+            Channel::Longitude,
+            Channel::Speed,
+            Channel::Heading,
+            Channel::Altitude,
+            Channel::Satellites,
+            Channel::HorizontalAccuracy,
+            Channel::GForceX,
+            Channel::GForceY,
+            Channel::GForceZ,
+            Channel::RotRateX,
+            Channel::RotRateY,
+            Channel::RotRateZ,
+        ]
+    }
+
+    // Column name, including the unit where there is one
+    pub fn header(&self, units: Units) -> String {
+        match self {
+            Channel::Latitude => "latitude (deg)".to_string(),
+            Channel::Longitude => "longitude (deg)".to_string(),
+            Channel::Speed => format!("speed ({})", units.speed()),
+            Channel::Heading => "heading (deg)".to_string(),
+            Channel::Altitude => format!("altitude ({})", units.distance()),
+            Channel::Satellites => "satellites".to_string(),
+            Channel::HorizontalAccuracy => format!("horizontal accuracy ({})", units.distance()),
+            Channel::GForceX => "g force x (g)".to_string(),
+            Channel::GForceY => "g force y (g)".to_string(),
+            Channel::GForceZ => "g force z (g)".to_string(),
+            Channel::RotRateX => "rotation rate x (deg/s)".to_string(),
+            Channel::RotRateY => "rotation rate y (deg/s)".to_string(),
+            Channel::RotRateZ => "rotation rate z (deg/s)".to_string(),
+        }
+    }
+
+    // The value of this channel for a sample, formatted for a CSV cell
+    pub fn value(&self, msg: &RbMessage, units: Units) -> String {
+        let (g_x, g_y, g_z) = msg.g_forces();
+        let (rot_x, rot_y, rot_z) = msg.rot_rates();
+        match self {
+            Channel::Latitude => format!("{:.7}", msg.gps_coordinates().latitude()),
+            Channel::Longitude => format!("{:.7}", msg.gps_coordinates().longitude()),
+            Channel::Speed => format!("{:.2}", units.convert_speed(msg.speed() as f64)),
+            Channel::Heading => format!("{:.2}", msg.heading() as f64 / 100000.0),
+            Channel::Altitude => format!("{:.2}", units.convert_distance(msg.altitude() as f64)),
+            Channel::Satellites => format!("{}", msg.satelites()),
+            Channel::HorizontalAccuracy => {
+                format!("{:.2}", units.convert_distance(msg.horiz_accuracy() as f64))
+            }
+            Channel::GForceX => format!("{:.3}", g_x as f64 / 1000.0),
+            Channel::GForceY => format!("{:.3}", g_y as f64 / 1000.0),
+            Channel::GForceZ => format!("{:.3}", g_z as f64 / 1000.0),
+            Channel::RotRateX => format!("{:.2}", rot_x as f64 / 100.0),
+            Channel::RotRateY => format!("{:.2}", rot_y as f64 / 100.0),
+            Channel::RotRateZ => format!("{:.2}", rot_z as f64 / 100.0),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Units {
+    Metric,   // kph and metres
+    Imperial, // mph and feet
+}
+
+impl Units {
+    pub fn speed(&self) -> &'static str {
+        match self {
+            Units::Metric => "kph",
+            Units::Imperial => "mph",
+        }
+    }
+
+    pub fn distance(&self) -> &'static str {
+        match self {
+            Units::Metric => "m",
+            Units::Imperial => "ft",
+        }
+    }
+
+    pub fn convert_speed(&self, kph: f64) -> f64 {
+        match self {
+            Units::Metric => kph,
+            Units::Imperial => kph / 1.609344,
+        }
+    }
+
+    pub fn convert_distance(&self, mm: f64) -> f64 {
+        match self {
+            Units::Metric => mm / 1000.0,
+            Units::Imperial => mm / 304.8,
+        }
+    }
+}
+
+// What the time column of an export is relative to
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TimeBase {
+    Elapsed,     // seconds since the first sample of the session
+    Utc,         // RFC 3339 timestamp of the sample
+    LapRelative, // seconds since the start of the sample's lap
+}
+
+impl TimeBase {
+    fn header(&self) -> &'static str {
+        match self {
+            TimeBase::Elapsed => "elapsed (s)",
+            TimeBase::Utc => "utc",
+            TimeBase::LapRelative => "lap time (s)",
+        }
+    }
+}
+
+pub struct CsvOptions {
+    pub channels: Vec<Channel>,
+    pub units: Units,
+    pub time_base: TimeBase,
+    pub track: Option<Track>, // Used to number the laps, everything is lap 0 without it
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            channels: Channel::all(),
+            units: Units::Metric,
+            time_base: TimeBase::Elapsed,
+            track: None,
+        }
+    }
+}
+
+impl Logger {
+    // Write one session out as CSV, one row per sample
+    pub fn export_csv<W: Write>(
+        &self,
+        session_id: u64,
+        options: &CsvOptions,
+        out: &mut W,
+    ) -> Result<(), Box<dyn Error>> {
+        let samples = self.get_session(session_id)?;
+        write_csv(&samples, options, out)
+    }
+}
+
+fn write_csv<W: Write>(
+    samples: &[RbMessage],
+    options: &CsvOptions,
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    let laps = match &options.track {
+        Some(track) => replay::lap_numbers(samples, track),
+        None => vec![0; samples.len()],
+    };
+
+    let mut header = vec![options.time_base.header().to_string(), "lap".to_string()];
+    for channel in options.channels.iter() {
+        header.push(channel.header(options.units));
+    }
+    writeln!(out, "{}", header.join(","))?;
+
+    let session_start = samples.first().and_then(|msg| msg.utc());
+    let mut lap_start = session_start;
+    for (i, msg) in samples.iter().enumerate() {
+        let utc = msg.utc();
+        if i > 0 && laps[i] != laps[i - 1] {
+            lap_start = utc;
+        }
+
+        let time = match options.time_base {
+            TimeBase::Elapsed => seconds_since(session_start, utc),
+            TimeBase::LapRelative => seconds_since(lap_start, utc),
+            TimeBase::Utc => match utc {
+                Some(utc) => utc.to_rfc3339_opts(SecondsFormat::Millis, true),
+                None => String::new(),
+            },
+        };
+
+        let mut row = vec![time, laps[i].to_string()];
+        for channel in options.channels.iter() {
+            row.push(channel.value(msg, options.units));
+        }
+        writeln!(out, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+// Blank if either end doesn't have a usable timestamp
+fn seconds_since(start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> String {
+    match (start, end) {
+        (Some(start), Some(end)) => {
+            format!("{:.3}", (end - start).num_milliseconds() as f64 / 1000.0)
+        }
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rbmini::message::Datetime;
+
+    // A short drive back and forth across a start/finish line at latitude 2.5
+    fn samples() -> Vec<RbMessage> {
+        let mut samples = Vec::new();
+        for (i, lat) in [1, 2, 3, 2, 1].iter().enumerate() {
+            let mut msg = RbMessage::new();
+            msg.update_coordinates(50000000, lat * 10000000);
+            let datetime = Datetime {
+                year: 2023,
+                month: 3,
+                day: 4,
+                hour: 10,
+                minute: 0,
+                second: i as u8,
+            };
+            msg.update_datetime(datetime, 500000000);
+            samples.push(msg);
+        }
+        samples
+    }
+
+    fn export(options: &CsvOptions) -> Vec<String> {
+        let mut out = Vec::new();
+        write_csv(&samples(), options, &mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_export_elapsed() {
+        let options = CsvOptions {
+            channels: vec![Channel::Latitude, Channel::Speed],
+            ..Default::default()
+        };
+        let lines = export(&options);
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "elapsed (s),lap,latitude (deg),speed (kph)");
+        assert_eq!(lines[1], "0.000,0,1.0000000,0.00");
+        assert_eq!(lines[5], "4.000,0,1.0000000,0.00");
+    }
+
+    #[test]
+    fn test_export_lap_relative() {
+        let options = CsvOptions {
+            channels: vec![Channel::Altitude],
+            units: Units::Imperial,
+            time_base: TimeBase::LapRelative,
+            track: Some(Track::new("Test".to_string(), (2.5, 0.0), (2.5, 10.0))),
+        };
+        let lines = export(&options);
+        assert_eq!(lines[0], "lap time (s),lap,altitude (ft)");
+        assert_eq!(lines[2], "1.000,0,0.00");
+        assert_eq!(lines[3], "0.000,1,0.00");
+        assert_eq!(lines[4], "0.000,2,0.00");
+        assert_eq!(lines[5], "1.000,2,0.00");
+    }
+
+    #[test]
+    fn test_export_utc() {
+        let options = CsvOptions {
+            channels: vec![],
+            time_base: TimeBase::Utc,
+            ..Default::default()
+        };
+        let lines = export(&options);
+        assert_eq!(lines[0], "utc,lap");
+        assert_eq!(lines[1], "2023-03-04T10:00:00.500Z,0");
+    }
+
+    #[test]
+    fn test_units() {
+        assert_eq!(Units::Metric.convert_distance(1500.0), 1.5);
+        assert_eq!(Units::Imperial.convert_distance(304.8), 1.0);
+        assert!((Units::Imperial.convert_speed(160.9344) - 100.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,102 @@
+use chrono::SecondsFormat;
+use std::error::Error;
+use std::io::Write;
+
+use rbmini::message::RbMessage;
+
+use crate::Logger;
+
+impl Logger {
+    // Write one session out as a GPX 1.1 track
+    pub fn export_gpx<W: Write>(&self, session_id: u64, out: &mut W) -> Result<(), Box<dyn Error>> {
+        let samples = self.get_session(session_id)?;
+        write_gpx(session_id, &samples, out)
+    }
+}
+
+fn write_gpx<W: Write>(
+    session_id: u64,
+    samples: &[RbMessage],
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<gpx version="1.1" creator="Openlaps" xmlns="http://www.topografix.com/GPX/1/1" xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v2">"#
+    )?;
+    writeln!(out, "  <trk>")?;
+    writeln!(out, "    <name>Openlaps session {}</name>", session_id)?;
+    writeln!(out, "    <trkseg>")?;
+    for msg in samples {
+        let coords = msg.gps_coordinates();
+        writeln!(
+            out,
+            r#"      <trkpt lat="{:.7}" lon="{:.7}">"#,
+            coords.latitude(),
+            coords.longitude()
+        )?;
+        writeln!(
+            out,
+            "        <ele>{:.3}</ele>",
+            msg.altitude() as f64 / 1000.0
+        )?;
+        if let Some(utc) = msg.utc() {
+            writeln!(
+                out,
+                "        <time>{}</time>",
+                utc.to_rfc3339_opts(SecondsFormat::Millis, true)
+            )?;
+        }
+        writeln!(out, "        <sat>{}</sat>", msg.satelites())?;
+        // GPX has no speed of its own, use the Garmin extension everyone reads
+        writeln!(out, "        <extensions>")?;
+        writeln!(out, "          <gpxtpx:TrackPointExtension>")?;
+        writeln!(
+            out,
+            "            <gpxtpx:speed>{:.3}</gpxtpx:speed>",
+            msg.speed() as f64 / 3.6
+        )?;
+        writeln!(
+            out,
+            "            <gpxtpx:course>{:.2}</gpxtpx:course>",
+            msg.heading() as f64 / 100000.0
+        )?;
+        writeln!(out, "          </gpxtpx:TrackPointExtension>")?;
+        writeln!(out, "        </extensions>")?;
+        writeln!(out, "      </trkpt>")?;
+    }
+    writeln!(out, "    </trkseg>")?;
+    writeln!(out, "  </trk>")?;
+    writeln!(out, "</gpx>")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rbmini::message::Datetime;
+
+    #[test]
+    fn test_write_gpx() {
+        let mut msg = RbMessage::new();
+        msg.update_coordinates(232887238, 426719035);
+        let datetime = Datetime {
+            year: 2022,
+            month: 1,
+            day: 10,
+            hour: 8,
+            minute: 51,
+            second: 8,
+        };
+        msg.update_datetime(datetime, 0);
+
+        let mut out = Vec::new();
+        write_gpx(42, &[msg], &mut out).unwrap();
+        let gpx = String::from_utf8(out).unwrap();
+        assert!(gpx.contains("<name>Openlaps session 42</name>"));
+        assert!(gpx.contains(r#"<trkpt lat="42.6719035" lon="23.2887238">"#));
+        assert!(gpx.contains("<time>2022-01-10T08:51:08.000Z</time>"));
+        assert!(gpx.contains("<gpxtpx:speed>0.000</gpxtpx:speed>"));
+        assert!(gpx.ends_with("</gpx>\n"));
+    }
+}
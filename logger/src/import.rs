@@ -0,0 +1,353 @@
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::BufRead;
+use std::time::SystemTime;
+
+use rbmini::message::{Datetime, RbMessage};
+
+use crate::Logger;
+
+// Apps whose CSV exports we can import
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Source {
+    RaceChrono,     // RaceChrono CSV v3 export
+    HarrysLapTimer, // Harry's LapTimer CSV export
+}
+
+impl Source {
+    // Does this row hold the column names?
+    fn is_header(&self, cells: &[String]) -> bool {
+        match self {
+            Source::RaceChrono => cells.first().is_some_and(|c| normalize(c) == "timestamp"),
+            Source::HarrysLapTimer => cells.iter().any(|c| normalize(c) == "latitude"),
+        }
+    }
+
+    // Speed unit used when the column name doesn't say
+    fn speed_unit(&self) -> &'static str {
+        match self {
+            Source::RaceChrono => "m/s",
+            Source::HarrysLapTimer => "km/h",
+        }
+    }
+}
+
+impl Logger {
+    // Import another app's CSV export as a new session, returns the id of
+    // the new session. start is when the recording began, which is needed
+    // for exports that only have the time since the start, like Harry's.
+    pub fn import_csv<R: BufRead>(
+        &self,
+        source: Source,
+        reader: R,
+        start: Option<SystemTime>,
+    ) -> Result<u64, Box<dyn Error>> {
+        let samples = parse(source, reader, start.map(DateTime::<Utc>::from))?;
+        let first = match samples.first() {
+            None => return Err("no samples found".into()),
+            Some(first) => first,
+        };
+
+        // Session ids are the start time in seconds, same as the dashboard
+        let sessions = self.get_sessions()?;
+        let mut session_id = first.utc().map_or(0, |utc| utc.timestamp() as u64);
+        while sessions.contains(&session_id) {
+            session_id += 1;
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+        for msg in samples.iter() {
+            self.write(session_id, &msg.to_json())?;
+        }
+        tx.commit()?;
+        Ok(session_id)
+    }
+}
+
+struct Columns {
+    index: HashMap<String, usize>,
+    units: HashMap<String, String>,
+}
+
+impl Columns {
+    fn new(header: &[String]) -> Self {
+        let mut index = HashMap::new();
+        let mut units = HashMap::new();
+        for (i, cell) in header.iter().enumerate() {
+            let name = normalize(cell);
+            // Some apps have the same channel from several sources, the
+            // first one is the one we want
+            if index.contains_key(&name) {
+                continue;
+            }
+            if let (Some(start), Some(end)) = (cell.find('('), cell.rfind(')')) {
+                if start < end {
+                    units.insert(name.clone(), cell[start + 1..end].trim().to_lowercase());
+                }
+            }
+            index.insert(name, i);
+        }
+        Columns { index, units }
+    }
+
+    // First of the names that's present in the header
+    fn find(&self, names: &[&str]) -> Option<(usize, &str)> {
+        for name in names {
+            if let Some(i) = self.index.get(*name) {
+                let unit = self.units.get(*name).map_or("", |u| u.as_str());
+                return Some((*i, unit));
+            }
+        }
+        None
+    }
+}
+
+fn parse<R: BufRead>(
+    source: Source,
+    reader: R,
+    start: Option<DateTime<Utc>>,
+) -> Result<Vec<RbMessage>, Box<dyn Error>> {
+    let mut columns: Option<Columns> = None;
+    let mut samples = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let cells = split(&line);
+
+        let cols = match &columns {
+            None => {
+                if source.is_header(&cells) {
+                    columns = Some(Columns::new(&cells));
+                }
+                continue;
+            }
+            Some(cols) => cols,
+        };
+
+        // Units and data source rows follow the header, skip anything
+        // that isn't a sample
+        let number = |names: &[&str]| -> Option<(f64, &str)> {
+            let (i, unit) = cols.find(names)?;
+            let value = cells.get(i)?.trim().parse::<f64>().ok()?;
+            Some((value, unit))
+        };
+        let (latitude, longitude) = match (number(&["latitude"]), number(&["longitude"])) {
+            (Some((lat, _)), Some((long, _))) => (lat, long),
+            _ => continue,
+        };
+
+        let utc = match number(&["timestamp", "utc", "unix_time"]) {
+            Some((seconds, _)) => from_seconds(Utc.timestamp_opt(0, 0).unwrap(), seconds),
+            None => match (number(&["time", "elapsed_time"]), start) {
+                // Harry's only has seconds since the start of the recording
+                (Some((seconds, _)), Some(start)) => from_seconds(start, seconds),
+                (Some(_), None) => return Err("no start time for the recording".into()),
+                (None, _) => continue,
+            },
+        };
+
+        let mut msg = RbMessage::new();
+        msg.update_fix(true);
+        msg.update_coordinates(
+            (longitude * 10000000.0).round() as i32,
+            (latitude * 10000000.0).round() as i32,
+        );
+        let (datetime, nanoseconds) = to_datetime(utc);
+        msg.update_datetime(datetime, nanoseconds);
+
+        if let Some((speed, unit)) = number(&["speed", "gps_speed"]) {
+            let unit = if unit.is_empty() {
+                source.speed_unit()
+            } else {
+                unit
+            };
+            let mm_per_sec = match unit {
+                "km/h" | "kph" => speed / 3.6 * 1000.0,
+                "mph" => speed * 1.609344 / 3.6 * 1000.0,
+                _ => speed * 1000.0,
+            };
+            msg.update_speed(mm_per_sec.round() as i32);
+        }
+        if let Some((heading, _)) = number(&["bearing", "heading", "course"]) {
+            msg.update_heading((heading * 100000.0).round() as i32);
+        }
+        if let Some((altitude, unit)) = number(&["altitude"]) {
+            let mm = match unit {
+                "ft" => altitude * 304.8,
+                _ => altitude * 1000.0,
+            };
+            msg.update_altitude(mm.round() as i32);
+        }
+        if let Some((accuracy, _)) = number(&["accuracy", "horizontal_accuracy"]) {
+            msg.update_horiz_accuracy((accuracy * 1000.0).round() as u32);
+        }
+        if let Some((satellites, _)) = number(&["satellites", "sats"]) {
+            msg.update_satelites(satellites as u8);
+        }
+        let g = |names: &[&str]| -> i16 {
+            number(names).map_or(0, |(g, _)| (g * 1000.0).round() as i16)
+        };
+        msg.update_g_forces(
+            g(&[
+                "longitudinal_acc",
+                "longitudinal_g",
+                "longitudinal_acceleration",
+            ]),
+            g(&["lateral_acc", "lateral_g", "lateral_acceleration"]),
+            g(&["vertical_acc", "vertical_g", "vertical_acceleration"]),
+        );
+
+        samples.push(msg);
+    }
+
+    if columns.is_none() {
+        return Err("no header row found".into());
+    }
+    Ok(samples)
+}
+
+fn from_seconds(base: DateTime<Utc>, seconds: f64) -> DateTime<Utc> {
+    base + chrono::Duration::microseconds((seconds * 1000000.0).round() as i64)
+}
+
+fn to_datetime(utc: DateTime<Utc>) -> (Datetime, i32) {
+    let datetime = Datetime {
+        year: utc.year() as u16,
+        month: utc.month() as u8,
+        day: utc.day() as u8,
+        hour: utc.hour() as u8,
+        minute: utc.minute() as u8,
+        second: utc.second() as u8,
+    };
+    (datetime, utc.nanosecond() as i32)
+}
+
+// Lower case, no units and underscores instead of spaces,
+// "Speed (km/h)" becomes "speed"
+fn normalize(name: &str) -> String {
+    let name = match name.find('(') {
+        Some(i) => &name[..i],
+        None => name,
+    };
+    name.trim().to_lowercase().replace([' ', '-'], "_")
+}
+
+// Split a CSV line, allowing for quoted cells
+fn split(line: &str) -> Vec<String> {
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                cell.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => cells.push(std::mem::take(&mut cell)),
+            c => cell.push(c),
+        }
+    }
+    cells.push(cell);
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RACECHRONO: &str = "This file is created using RaceChrono v7.4.5 ( http://racechrono.com/ ).
+Format,3
+Session title,\"Sonoma, Saturday\"
+Session type,Lap timing
+Track name,Sonoma Raceway
+
+timestamp,fragment_id,lap_number,elapsed_time,distance_traveled,accuracy,altitude,bearing,fix_type,latitude,longitude,satellites,speed,lateral_acc,longitudinal_acc
+unix time,,,s,m,m,m,deg,,deg,deg,sats,m/s,G,G
+,,,,,100: gps,100: gps,100: gps,100: gps,100: gps,100: gps,100: gps,100: gps,101: acc,101: acc
+1678000000.000,0,,0.000,0.000,1.5,30.0,90.0,3,38.1612345,-122.4543210,12,20.0,0.50,-0.25
+1678000000.040,0,,0.040,0.800,,,,,,,,,0.52,-0.20
+1678000000.080,0,,0.080,1.600,1.5,30.1,91.0,3,38.1612350,-122.4543000,12,20.5,0.55,-0.10
+";
+
+    const HARRYS: &str = "# Harry's GPS LapTimer
+# Export of Sonoma Raceway
+Time (sec),Lap,Latitude,Longitude,Altitude (m),Speed (mph),Heading,Lateral G,Longitudinal G,Satellites
+0.00,0,38.1612345,-122.4543210,30.0,60.0,90.0,0.50,-0.25,9
+0.10,0,38.1612350,-122.4543000,30.1,61.0,91.0,0.55,-0.10,9
+";
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("a,\"b, c\",,d"), vec!["a", "b, c", "", "d"]);
+        assert_eq!(split("\"say \"\"hi\"\"\""), vec!["say \"hi\""]);
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("Speed (km/h)"), "speed");
+        assert_eq!(normalize("Lateral G"), "lateral_g");
+        assert_eq!(normalize("timestamp"), "timestamp");
+    }
+
+    #[test]
+    fn test_parse_racechrono() {
+        let samples = parse(Source::RaceChrono, RACECHRONO.as_bytes(), None).unwrap();
+        assert_eq!(samples.len(), 2); // The accelerometer only row is skipped
+
+        let msg = &samples[0];
+        assert!(msg.is_valid_fix());
+        assert_eq!(msg.gps_coordinates().latitude(), 38.1612345);
+        assert_eq!(msg.gps_coordinates().longitude(), -122.454321);
+        assert_eq!(msg.utc().unwrap().timestamp(), 1678000000);
+        assert!((msg.speed() - 72.0).abs() < 0.001);
+        assert_eq!(msg.heading(), 9000000);
+        assert_eq!(msg.altitude(), 30000);
+        assert_eq!(msg.horiz_accuracy(), 1500);
+        assert_eq!(msg.satelites(), 12);
+        assert_eq!(msg.g_forces(), (-250, 500, 0));
+
+        let utc = samples[1].utc().unwrap();
+        assert_eq!(utc.timestamp_subsec_millis(), 80);
+    }
+
+    #[test]
+    fn test_parse_harrys() {
+        assert!(parse(Source::HarrysLapTimer, HARRYS.as_bytes(), None).is_err());
+        let start = Utc.timestamp_opt(1678000000, 0).unwrap();
+        let samples = parse(Source::HarrysLapTimer, HARRYS.as_bytes(), Some(start)).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].speed().round(), 97.0); // 60 mph
+        assert_eq!(samples[0].satelites(), 9);
+        assert_eq!(samples[0].g_forces(), (-250, 500, 0));
+        let elapsed = samples[1].utc().unwrap() - samples[0].utc().unwrap();
+        assert_eq!(elapsed.num_milliseconds(), 100);
+        assert_eq!(samples[0].utc(), Some(start));
+    }
+
+    #[test]
+    fn test_parse_no_header() {
+        assert!(parse(Source::RaceChrono, "1,2,3\n".as_bytes(), None).is_err());
+    }
+
+    #[test]
+    fn test_import_csv() {
+        let l = Logger::default();
+        let session_id = l
+            .import_csv(Source::RaceChrono, RACECHRONO.as_bytes(), None)
+            .unwrap();
+        assert_eq!(session_id, 1678000000);
+        assert_eq!(l.get_session(session_id).unwrap().len(), 2);
+
+        // Importing the same file again doesn't clobber the first import
+        let session_id = l
+            .import_csv(Source::RaceChrono, RACECHRONO.as_bytes(), None)
+            .unwrap();
+        assert_eq!(session_id, 1678000001);
+    }
+}
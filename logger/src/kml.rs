@@ -0,0 +1,147 @@
+use std::error::Error;
+use std::io::Write;
+
+use rbmini::message::RbMessage;
+use timer::{LapType, Track};
+
+use crate::replay;
+use crate::Logger;
+
+impl Logger {
+    // Write one session out as KML with a line per lap. Without a track
+    // there is nothing to split on and the session is a single line.
+    pub fn export_kml<W: Write>(
+        &self,
+        session_id: u64,
+        track: Option<&Track>,
+        out: &mut W,
+    ) -> Result<(), Box<dyn Error>> {
+        let samples = self.get_session(session_id)?;
+        write_kml(session_id, &samples, track, out)
+    }
+}
+
+fn write_kml<W: Write>(
+    session_id: u64,
+    samples: &[RbMessage],
+    track: Option<&Track>,
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    let laps = match track {
+        Some(track) => replay::lap_types(samples, track),
+        None => vec![LapType::Out; samples.len()],
+    };
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#)?;
+    writeln!(out, "  <Document>")?;
+    writeln!(out, "    <name>Openlaps session {}</name>", session_id)?;
+
+    let mut start = 0;
+    while start < samples.len() {
+        let lap = laps[start];
+        let end = laps[start..]
+            .iter()
+            .position(|l| *l != lap)
+            .map_or(samples.len(), |len| start + len);
+
+        writeln!(out, "    <Placemark>")?;
+        match lap {
+            LapType::Out => writeln!(out, "      <name>Out lap</name>")?,
+            LapType::In => writeln!(out, "      <name>In lap</name>")?,
+            LapType::Lap(num) => writeln!(out, "      <name>Lap {}</name>", num)?,
+        };
+        writeln!(out, "      <LineString>")?;
+        writeln!(out, "        <tessellate>1</tessellate>")?;
+        writeln!(out, "        <altitudeMode>clampToGround</altitudeMode>")?;
+        writeln!(out, "        <coordinates>")?;
+        // Join the line up with the first sample of the next lap so there
+        // are no gaps at the start/finish line
+        let last = if end < samples.len() { end + 1 } else { end };
+        for msg in &samples[start..last] {
+            let coords = msg.gps_coordinates();
+            writeln!(
+                out,
+                "          {:.7},{:.7},{:.3}",
+                coords.longitude(),
+                coords.latitude(),
+                msg.altitude() as f64 / 1000.0
+            )?;
+        }
+        writeln!(out, "        </coordinates>")?;
+        writeln!(out, "      </LineString>")?;
+        writeln!(out, "    </Placemark>")?;
+
+        start = end;
+    }
+
+    writeln!(out, "  </Document>")?;
+    writeln!(out, "</kml>")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timer::PitLane;
+
+    #[test]
+    fn test_write_kml() {
+        let track = Track::new("Test Track".to_string(), (2.5, 0.0), (2.5, 10.0));
+        let mut samples = Vec::new();
+        for lat in [1, 2, 3, 4, 3, 2] {
+            let mut msg = RbMessage::new();
+            msg.update_coordinates(50000000, lat * 10000000);
+            samples.push(msg);
+        }
+
+        let mut out = Vec::new();
+        write_kml(7, &samples, Some(&track), &mut out).unwrap();
+        let kml = String::from_utf8(out).unwrap();
+        assert_eq!(kml.matches("<Placemark>").count(), 3);
+        assert!(kml.contains("<name>Out lap</name>"));
+        assert!(kml.contains("<name>Lap 1</name>"));
+        assert!(kml.contains("<name>Lap 2</name>"));
+        assert!(kml.contains("          5.0000000,1.0000000,0.000\n"));
+
+        let mut out = Vec::new();
+        write_kml(7, &samples, None, &mut out).unwrap();
+        let kml = String::from_utf8(out).unwrap();
+        assert_eq!(kml.matches("<Placemark>").count(), 1);
+    }
+
+    #[test]
+    fn test_write_kml_pits() {
+        // Round once, into the pits before start/finish and back out
+        let mut track = Track::new("Test Track".to_string(), (2.5, 0.0), (2.5, 2.0));
+        track.set_pit_lane(PitLane::from_area(&[
+            (0.5, 0.5),
+            (0.5, 1.5),
+            (1.7, 1.5),
+            (1.7, 0.5),
+        ]));
+        let mut samples = Vec::new();
+        for (lat, long) in [
+            (2.0, 1.0),
+            (3.0, 1.0),
+            (4.0, 3.0),
+            (2.0, 5.0),
+            (1.0, 3.0),
+            (1.0, 1.0),
+            (1.5, 2.0),
+            (2.0, 1.0),
+        ] {
+            let mut msg = RbMessage::new();
+            msg.update_coordinates((long * 10000000.0) as i32, (lat * 10000000.0) as i32);
+            samples.push(msg);
+        }
+
+        let mut out = Vec::new();
+        write_kml(7, &samples, Some(&track), &mut out).unwrap();
+        let kml = String::from_utf8(out).unwrap();
+        assert_eq!(kml.matches("<Placemark>").count(), 4);
+        assert_eq!(kml.matches("<name>Out lap</name>").count(), 2);
+        assert!(kml.contains("<name>Lap 1</name>"));
+        assert!(kml.contains("<name>In lap</name>"));
+    }
+}
@@ -0,0 +1,163 @@
+use rbmini::message::RbMessage;
+use rusqlite::types::Type;
+use rusqlite::{named_params, Error, Result, Row};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use timer::{LapType, Motion};
+
+use crate::Logger;
+
+// A completed lap as the timer saw it
+#[derive(Clone, Debug, PartialEq)]
+pub struct LapRecord {
+    pub session_id: u64,
+    pub lap_type: LapType,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub lap_time: Duration,
+    pub sectors: Vec<Duration>, // Sector times in order, empty if the track has no sectors
+    pub valid: bool,
+}
+
+// What the car was doing at a sample, for the timer's lap metrics. The
+// device's x axis is front to back and y is side to side, in milli-g.
+pub fn motion(sample: &RbMessage) -> Motion {
+    let (x, y, _) = sample.g_forces();
+    Motion {
+        speed: sample.speed(),
+        lateral_g: y as f32 / 1000.0,
+        longitudinal_g: x as f32 / 1000.0,
+    }
+}
+
+pub(crate) fn to_millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+pub(crate) fn from_millis(ms: i64) -> SystemTime {
+    if ms < 0 {
+        UNIX_EPOCH - Duration::from_millis(ms.unsigned_abs())
+    } else {
+        UNIX_EPOCH + Duration::from_millis(ms as u64)
+    }
+}
+
+fn lap_record(row: &Row) -> Result<LapRecord> {
+    let number: u16 = row.get("number")?;
+    let lap_type = match row.get::<_, String>("lap_type")?.as_str() {
+        "out" => LapType::Out,
+        "in" => LapType::In,
+        _ => LapType::Lap(number),
+    };
+    let sectors: String = row.get("sectors")?;
+    let sectors: Vec<u64> = serde_json::from_str(&sectors)
+        .map_err(|e| Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))?;
+    Ok(LapRecord {
+        session_id: row.get("session_id")?,
+        lap_type,
+        start: from_millis(row.get("start_time")?),
+        end: from_millis(row.get("end_time")?),
+        lap_time: Duration::from_millis(row.get("lap_time")?),
+        sectors: sectors.into_iter().map(Duration::from_millis).collect(),
+        valid: row.get("valid")?,
+    })
+}
+
+impl Logger {
+    pub fn write_lap(&self, lap: &LapRecord) -> Result<()> {
+        let (lap_type, number) = match lap.lap_type {
+            LapType::Out => ("out", 0),
+            LapType::In => ("in", 0),
+            LapType::Lap(num) => ("lap", num),
+        };
+        let sectors: Vec<u64> = lap.sectors.iter().map(|s| s.as_millis() as u64).collect();
+        let mut stmt = self.conn.prepare_cached(
+            "INSERT INTO laps
+                (session_id, number, lap_type, start_time, end_time, lap_time, sectors, valid)
+             VALUES
+                (:session_id, :number, :lap_type, :start_time, :end_time, :lap_time, :sectors, :valid)",
+        )?;
+        stmt.execute(named_params! {
+            ":session_id": lap.session_id,
+            ":number": number,
+            ":lap_type": lap_type,
+            ":start_time": to_millis(lap.start),
+            ":end_time": to_millis(lap.end),
+            ":lap_time": lap.lap_time.as_millis() as u64,
+            ":sectors": serde_json::to_string(&sectors).unwrap(),
+            ":valid": lap.valid,
+        })?;
+        Ok(())
+    }
+
+    // Every lap of a session in the order they were driven
+    pub fn get_laps(&self, session_id: u64) -> Result<Vec<LapRecord>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT * FROM laps WHERE session_id=? ORDER BY start_time, id")?;
+        let laps = stmt.query_map([session_id], lap_record)?;
+        laps.collect()
+    }
+
+    // The fastest valid timed laps across every session
+    pub fn get_best_laps(&self, limit: usize) -> Result<Vec<LapRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT * FROM laps WHERE valid=1 AND lap_type='lap'
+             ORDER BY lap_time, start_time LIMIT ?",
+        )?;
+        let laps = stmt.query_map([limit], lap_record)?;
+        laps.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lap(session_id: u64, lap_type: LapType, start: u64, lap_time: u64) -> LapRecord {
+        let start = UNIX_EPOCH + Duration::from_secs(start);
+        LapRecord {
+            session_id,
+            lap_type,
+            start,
+            end: start + Duration::from_millis(lap_time),
+            lap_time: Duration::from_millis(lap_time),
+            sectors: vec![],
+            valid: true,
+        }
+    }
+
+    #[test]
+    fn test_write_lap() {
+        let l = Logger::default();
+        let mut lap1 = lap(1, LapType::Lap(1), 1100, 95123);
+        lap1.sectors = vec![Duration::from_millis(30000), Duration::from_millis(65123)];
+        l.write_lap(&lap(1, LapType::Out, 1000, 100000)).unwrap();
+        l.write_lap(&lap1).unwrap();
+        l.write_lap(&lap(2, LapType::Lap(1), 2000, 99000)).unwrap();
+
+        let laps = l.get_laps(1).unwrap();
+        assert_eq!(laps.len(), 2);
+        assert_eq!(laps[0].lap_type, LapType::Out);
+        assert_eq!(laps[1], lap1);
+    }
+
+    #[test]
+    fn test_get_best_laps() {
+        let l = Logger::default();
+        let mut invalid = lap(1, LapType::Lap(2), 1200, 90000);
+        invalid.valid = false;
+        l.write_lap(&lap(1, LapType::Out, 1000, 80000)).unwrap();
+        l.write_lap(&lap(1, LapType::Lap(1), 1100, 95000)).unwrap();
+        l.write_lap(&invalid).unwrap();
+        l.write_lap(&lap(2, LapType::Lap(1), 2000, 94000)).unwrap();
+
+        let best = l.get_best_laps(10).unwrap();
+        let times: Vec<u128> = best.iter().map(|l| l.lap_time.as_millis()).collect();
+        assert_eq!(times, vec![94000, 95000]);
+        assert_eq!(best[0].session_id, 2);
+    }
+}
@@ -1,11 +1,113 @@
 use rusqlite::{named_params, Connection, Result};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use rbmini::message::RbMessage;
 
+use query::SessionQuery;
+
+pub mod analysis;
+pub mod binlog;
+pub mod export;
+pub mod gpx;
+pub mod import;
+pub mod kml;
+pub mod laps;
+pub mod motion;
+pub mod performance;
+pub mod query;
+pub mod raw;
+pub mod recovery;
+mod replay;
+pub mod retention;
+pub mod sessions;
+
 pub struct Logger {
-    path: &'static Path,
+    path: PathBuf,
     conn: Connection,
+    keep_raw: bool, // Keep the raw frames from the device as well as the decoded telemetry
+}
+
+// Schema changes, applied in order when the database is opened. The number
+// of migrations applied is kept in the database's user_version.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS telemetry (
+        id INTEGER PRIMARY KEY,
+        session_id INTEGER NOT NULL,
+        value TEXT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS raw_frames (
+        id INTEGER PRIMARY KEY,
+        session_id INTEGER NOT NULL,
+        received_at INTEGER NOT NULL,
+        checksum_ok INTEGER NOT NULL,
+        frame BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS raw_frames_session ON raw_frames (session_id)",
+    // Lets pruned sessions be given back to the filesystem a bit at a time.
+    // New databases get this before any tables are made, older ones only
+    // switch over on a full VACUUM, see Logger::compact.
+    "PRAGMA auto_vacuum = INCREMENTAL",
+    "CREATE TABLE IF NOT EXISTS sessions (
+        session_id INTEGER PRIMARY KEY,
+        starred INTEGER NOT NULL DEFAULT 0
+    )",
+    // Sample time in ms since the epoch so sessions can be read by time
+    // range without parsing every row
+    "ALTER TABLE telemetry ADD COLUMN timestamp INTEGER GENERATED ALWAYS AS (
+        CASE WHEN json_valid(value) THEN
+            unixepoch(printf('%04d-%02d-%02d %02d:%02d:%02d',
+                value->>'$.datetime.year', value->>'$.datetime.month',
+                value->>'$.datetime.day', value->>'$.datetime.hour',
+                value->>'$.datetime.minute', value->>'$.datetime.second'))
+            * 1000 + (value->>'$.nanoseconds') / 1000000
+        END
+    ) VIRTUAL;
+    CREATE INDEX IF NOT EXISTS telemetry_session ON telemetry (session_id, timestamp)",
+    // Times are in ms, sectors is a JSON array of sector times in ms
+    "CREATE TABLE IF NOT EXISTS laps (
+        id INTEGER PRIMARY KEY,
+        session_id INTEGER NOT NULL,
+        number INTEGER NOT NULL,
+        lap_type TEXT NOT NULL,
+        start_time INTEGER NOT NULL,
+        end_time INTEGER NOT NULL,
+        lap_time INTEGER NOT NULL,
+        sectors TEXT NOT NULL DEFAULT '[]',
+        valid INTEGER NOT NULL DEFAULT 1
+    );
+    CREATE INDEX IF NOT EXISTS laps_session ON laps (session_id);
+    CREATE INDEX IF NOT EXISTS laps_lap_time ON laps (lap_time)",
+    // Sessions we logged live, a session that started but never ended was
+    // cut short. Times are in ms since the epoch.
+    "ALTER TABLE sessions ADD COLUMN started_at INTEGER;
+    ALTER TABLE sessions ADD COLUMN ended_at INTEGER;
+    ALTER TABLE sessions ADD COLUMN recovered INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE sessions ADD COLUMN name TEXT",
+    // Acceleration, drag and braking times. Times are in ms, distance in
+    // metres and speed in kph.
+    "CREATE TABLE IF NOT EXISTS performance (
+        id INTEGER PRIMARY KEY,
+        session_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        start_time INTEGER NOT NULL,
+        time INTEGER NOT NULL,
+        distance REAL NOT NULL,
+        speed REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS performance_session ON performance (session_id)",
+];
+
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    // Only takes effect while the database is empty
+    if version == 0 {
+        conn.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(migration)?;
+        conn.pragma_update(None, "user_version", i + 1)?;
+    }
+    Ok(())
 }
 
 impl Default for Logger {
@@ -14,41 +116,32 @@ impl Default for Logger {
             Err(e) => panic!("Failed to open in memory database: {}", e),
             Ok(c) => c,
         };
-        if let Err(e) = conn.execute(
-            "CREATE TABLE IF NOT EXISTS telemetry (
-                id INTEGER PRIMARY KEY,
-                session_id INTEGER NOT NULL,
-                value TEXT NOT NULL
-            )",
-            [],
-        ) {
-            panic!("Failed to create table: {}", e)
+        if let Err(e) = migrate(&conn) {
+            panic!("Failed to create tables: {}", e)
         };
 
         Logger {
-            path: Path::new(Path::new("")),
+            path: PathBuf::new(),
             conn,
+            keep_raw: false,
         }
     }
 }
 
 impl Logger {
-    pub fn new(path: &'static Path) -> Logger {
-        let conn = match Connection::open(path) {
+    pub fn new<P: AsRef<Path>>(path: P) -> Logger {
+        let conn = match Connection::open(path.as_ref()) {
             Err(e) => panic!("Failed to open database: {}", e),
             Ok(c) => c,
         };
-        if let Err(e) = conn.execute(
-            "CREATE TABLE IF NOT EXISTS telemetry (
-                id INTEGER PRIMARY KEY,
-                session_id INTEGER NOT NULL,
-                value TEXT NOT NULL
-            )",
-            [],
-        ) {
-            panic!("Failed to create table: {}", e)
+        if let Err(e) = migrate(&conn) {
+            panic!("Failed to create tables: {}", e)
         };
-        Logger { path, conn }
+        Logger {
+            path: path.as_ref().to_path_buf(),
+            conn,
+            keep_raw: false,
+        }
     }
 
     pub fn write(&self, session_id: u64, line: &str) -> Result<(), String> {
@@ -68,7 +161,7 @@ impl Logger {
     }
 
     pub fn path(&self) -> &Path {
-        self.path
+        &self.path
     }
 
     // Return the last row set in the telemetry table
@@ -76,7 +169,7 @@ impl Logger {
         let mut stmt = self
             .conn
             .prepare("SELECT value FROM telemetry ORDER BY id DESC LIMIT 1")?;
-        let mut values = stmt.query_map([], |row| Ok(row.get(0)?))?;
+        let mut values = stmt.query_map([], |row| row.get(0))?;
         if let Some(value) = values.next() {
             return value;
         }
@@ -89,7 +182,7 @@ impl Logger {
             .conn
             .prepare("SELECT session_id FROM telemetry GROUP BY session_id")
             .unwrap();
-        let values = stmt.query_map([], |row| Ok(row.get(0)?))?;
+        let values = stmt.query_map([], |row| row.get(0))?;
         let mut sessions: Vec<u64> = Vec::new();
         for value in values {
             // TODO handle errors
@@ -98,18 +191,12 @@ impl Logger {
         Ok(sessions)
     }
 
-    // Get all the data for a specific session
+    // Get all the data for a specific session, see query_session for
+    // reading a long session a bit at a time
     // TODO create a common lap datapoint struct!
     pub fn get_session(&self, session_id: u64) -> Result<Vec<RbMessage>> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT value FROM telemetry WHERE session_id=?")?;
-        let values = stmt.query_map([session_id], |row| Ok(row.get(0)?))?;
-        let mut v: Vec<RbMessage> = Vec::new();
-        for value in values {
-            v.push(serde_json::from_value(value.unwrap()).unwrap());
-        }
-        Ok(v)
+        self.query_session(session_id, SessionQuery::default())
+            .collect()
     }
 }
 
@@ -0,0 +1,155 @@
+use std::time::{Duration, SystemTime};
+
+// When to start and stop logging on our own. Start and stop speeds are
+// apart so creeping along in the paddock doesn't flap between the two.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MotionConfig {
+    pub start_speed: f32, // kph, faster than this for start_after starts a session
+    pub start_after: Duration, // How long we have to be moving
+    pub stop_speed: f32,  // kph, slower than this for stop_after ends the session
+    pub stop_after: Duration, // How long we have to be stopped
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        MotionConfig {
+            start_speed: 8.0, // About 5 mph
+            start_after: Duration::from_secs(2),
+            stop_speed: 3.0,
+            stop_after: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MotionEvent {
+    Start(SystemTime), // When we first started moving
+    Stop(SystemTime),  // When we first stopped
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Idle(Option<SystemTime>),    // Moving since, if we are
+    Logging(Option<SystemTime>), // Stopped since, if we are
+}
+
+// Feed it every sample, it says when a session should start and stop
+pub struct MotionDetector {
+    config: MotionConfig,
+    state: State,
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig) -> Self {
+        MotionDetector {
+            config,
+            state: State::Idle(None),
+        }
+    }
+
+    pub fn is_logging(&self) -> bool {
+        matches!(self.state, State::Logging(_))
+    }
+
+    // Waiting to see if we've really started or stopped. Samples from now on
+    // are part of the session if it starts, or after its end if it stops.
+    pub fn is_pending(&self) -> bool {
+        matches!(self.state, State::Idle(Some(_)) | State::Logging(Some(_)))
+    }
+
+    pub fn update(&mut self, speed: f32, at: SystemTime) -> Option<MotionEvent> {
+        let held = |since: SystemTime, hold: Duration| {
+            at.duration_since(since).unwrap_or(Duration::ZERO) >= hold
+        };
+        match self.state {
+            State::Idle(moving_since) => {
+                if speed <= self.config.start_speed {
+                    self.state = State::Idle(None);
+                    return None;
+                }
+                let since = moving_since.unwrap_or(at);
+                if held(since, self.config.start_after) {
+                    self.state = State::Logging(None);
+                    return Some(MotionEvent::Start(since));
+                }
+                self.state = State::Idle(Some(since));
+            }
+            State::Logging(stopped_since) => {
+                if speed >= self.config.stop_speed {
+                    self.state = State::Logging(None);
+                    return None;
+                }
+                let since = stopped_since.unwrap_or(at);
+                if held(since, self.config.stop_after) {
+                    self.state = State::Idle(None);
+                    return Some(MotionEvent::Stop(since));
+                }
+                self.state = State::Logging(Some(since));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    // Feed speeds a second apart, returning the events and when they happened
+    fn drive(detector: &mut MotionDetector, speeds: &[f32]) -> Vec<(usize, MotionEvent)> {
+        speeds
+            .iter()
+            .enumerate()
+            .filter_map(|(i, speed)| {
+                let at = UNIX_EPOCH + Duration::from_secs(i as u64);
+                detector.update(*speed, at).map(|e| (i, e))
+            })
+            .collect()
+    }
+
+    fn secs(s: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(s)
+    }
+
+    #[test]
+    fn test_start() {
+        let mut detector = MotionDetector::new(MotionConfig::default());
+        let events = drive(&mut detector, &[0.0, 10.0, 10.0]);
+        assert!(events.is_empty());
+        assert!(detector.is_pending());
+        let events = drive(&mut detector, &[0.0, 10.0, 10.0, 10.0, 10.0]);
+        assert_eq!(events, vec![(3, MotionEvent::Start(secs(1)))]);
+        assert!(detector.is_logging());
+        assert!(!detector.is_pending());
+    }
+
+    #[test]
+    fn test_start_needs_to_be_held() {
+        let mut detector = MotionDetector::new(MotionConfig::default());
+        let events = drive(&mut detector, &[10.0, 10.0, 5.0, 10.0, 10.0, 0.0]);
+        assert!(events.is_empty());
+        assert!(!detector.is_logging());
+    }
+
+    #[test]
+    fn test_stop() {
+        let config = MotionConfig {
+            start_after: Duration::ZERO,
+            stop_after: Duration::from_secs(3),
+            ..Default::default()
+        };
+        let mut detector = MotionDetector::new(config);
+        // Crawling at 5kph is below the start speed but above the stop speed
+        let speeds = [20.0, 5.0, 0.0, 0.0, 5.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        let events = drive(&mut detector, &speeds);
+        assert_eq!(
+            events,
+            vec![
+                (0, MotionEvent::Start(secs(0))),
+                (8, MotionEvent::Stop(secs(5)))
+            ]
+        );
+        assert!(!detector.is_logging());
+    }
+}
@@ -0,0 +1,91 @@
+use rusqlite::{named_params, Result, Row};
+use std::time::Duration;
+
+use timer::PerformanceResult;
+
+use crate::laps::{from_millis, to_millis};
+use crate::Logger;
+
+fn performance_result(row: &Row) -> Result<PerformanceResult> {
+    Ok(PerformanceResult {
+        name: row.get("name")?,
+        start: from_millis(row.get("start_time")?),
+        time: Duration::from_millis(row.get("time")?),
+        distance: row.get("distance")?,
+        speed: row.get("speed")?,
+    })
+}
+
+impl Logger {
+    pub fn write_performance(&self, session_id: u64, result: &PerformanceResult) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(
+            "INSERT INTO performance (session_id, name, start_time, time, distance, speed)
+             VALUES (:session_id, :name, :start_time, :time, :distance, :speed)",
+        )?;
+        stmt.execute(named_params! {
+            ":session_id": session_id,
+            ":name": result.name,
+            ":start_time": to_millis(result.start),
+            ":time": result.time.as_millis() as u64,
+            ":distance": result.distance,
+            ":speed": result.speed,
+        })?;
+        Ok(())
+    }
+
+    // Every result of a session in the order they were made
+    pub fn get_performance(&self, session_id: u64) -> Result<Vec<PerformanceResult>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT * FROM performance WHERE session_id=? ORDER BY start_time + time, id",
+        )?;
+        let results = stmt.query_map([session_id], performance_result)?;
+        results.collect()
+    }
+
+    // The quickest times for one measurement, e.g. "0-100 km/h", across
+    // every session
+    pub fn get_best_performance(&self, name: &str, limit: usize) -> Result<Vec<PerformanceResult>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT * FROM performance WHERE name=? ORDER BY time, start_time LIMIT ?")?;
+        let results = stmt.query_map((name, limit), performance_result)?;
+        results.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn result(name: &str, start: u64, time: u64) -> PerformanceResult {
+        PerformanceResult {
+            name: name.to_string(),
+            start: UNIX_EPOCH + Duration::from_secs(start),
+            time: Duration::from_millis(time),
+            distance: 100.5,
+            speed: 100.0,
+        }
+    }
+
+    #[test]
+    fn test_performance() {
+        let l = Logger::default();
+        let quick = result("0-100 km/h", 20, 4500);
+        l.write_performance(1, &result("0-100 km/h", 10, 5200))
+            .unwrap();
+        l.write_performance(1, &result("1/4 mile", 10, 13100))
+            .unwrap();
+        l.write_performance(2, &quick).unwrap();
+
+        let results = l.get_performance(1).unwrap();
+        let names: Vec<&str> = results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["0-100 km/h", "1/4 mile"]);
+
+        let best = l.get_best_performance("0-100 km/h", 1).unwrap();
+        assert_eq!(best, vec![quick]);
+
+        l.delete_session(2).unwrap();
+        assert!(l.get_performance(2).unwrap().is_empty());
+    }
+}
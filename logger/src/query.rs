@@ -0,0 +1,381 @@
+use rusqlite::types::Type;
+use rusqlite::{named_params, Error, Result};
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::time::SystemTime;
+
+use rbmini::message::RbMessage;
+use timer::Track;
+
+use crate::laps::{from_millis, to_millis};
+use crate::replay::{self, LapCounter};
+use crate::Logger;
+
+// Rows fetched from the database at a time
+const CHUNK_SIZE: usize = 500;
+
+// Thinning out a session that's too long to look at sample by sample
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Decimation {
+    None,
+    EveryNth(usize), // Keep every nth sample
+    MinMax(usize),   // Keep the slowest and fastest sample of each bucket of this many samples
+}
+
+// Which parts of a session to read
+pub struct SessionQuery {
+    pub from: Option<SystemTime>,          // Samples at or after this time
+    pub to: Option<SystemTime>,            // Samples at or before this time
+    pub laps: Option<RangeInclusive<u16>>, // Only these laps, the out lap is lap 0
+    pub track: Option<Track>,              // Needed to count laps, laps is an error without it
+    pub decimation: Decimation,
+}
+
+impl Default for SessionQuery {
+    fn default() -> Self {
+        SessionQuery {
+            from: None,
+            to: None,
+            laps: None,
+            track: None,
+            decimation: Decimation::None,
+        }
+    }
+}
+
+impl Logger {
+    // Read a session a chunk at a time rather than all at once
+    pub fn query_session(&self, session_id: u64, query: SessionQuery) -> SessionIter<'_> {
+        let counter = match (&query.laps, &query.track) {
+            (Some(_), Some(track)) => Some(LapCounter::new(track)),
+            _ => None,
+        };
+        // Laps can't be counted without a track, rather than hand out the
+        // whole session the first thing out is an error
+        let error = match (&query.laps, &query.track) {
+            (Some(_), None) => Some(Error::ToSqlConversionFailure(
+                "filtering by lap needs a track".into(),
+            )),
+            _ => None,
+        };
+        let done = error.is_some();
+        SessionIter {
+            logger: self,
+            session_id,
+            query,
+            counter,
+            error,
+            last_id: 0,
+            rows: VecDeque::new(),
+            done,
+            seen: 0,
+            bucket: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+struct Row {
+    id: i64,
+    timestamp: Option<i64>, // ms since the epoch
+    speed: i64,
+    lat: i64,
+    long: i64,
+    value: String,
+}
+
+pub struct SessionIter<'a> {
+    logger: &'a Logger,
+    session_id: u64,
+    query: SessionQuery,
+    counter: Option<LapCounter>,
+    error: Option<Error>, // Handed out before anything else
+    last_id: i64,
+    rows: VecDeque<Row>,
+    done: bool,
+    seen: usize,            // Samples that made it through the filters
+    bucket: Vec<Row>,       // Current bucket for min/max decimation
+    pending: VecDeque<Row>, // Rows ready to be handed out
+}
+
+impl<'a> SessionIter<'a> {
+    // Refill the row buffer, false once the session is exhausted
+    fn fetch(&mut self) -> Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+        // Lap counting has to see every sample from the start of the
+        // session, so the time filter is applied after counting instead
+        let (from, to) = match self.counter {
+            Some(_) => (None, None),
+            None => (self.query.from.map(to_millis), self.query.to.map(to_millis)),
+        };
+        let mut stmt = self.logger.conn.prepare_cached(
+            "SELECT id, timestamp, iif(json_valid(value), value->>'$.speed', NULL),
+                iif(json_valid(value), value->>'$.coordinates.latitude', NULL),
+                iif(json_valid(value), value->>'$.coordinates.longitude', NULL), value
+             FROM telemetry
+             WHERE session_id=:session_id AND id>:after
+                AND (:from IS NULL OR timestamp>=:from) AND (:to IS NULL OR timestamp<=:to)
+             ORDER BY id LIMIT :limit",
+        )?;
+        let rows = stmt.query_map(
+            named_params! {
+                ":session_id": self.session_id,
+                ":after": self.last_id,
+                ":from": from,
+                ":to": to,
+                ":limit": CHUNK_SIZE,
+            },
+            |row| {
+                Ok(Row {
+                    id: row.get(0)?,
+                    timestamp: row.get(1)?,
+                    speed: row.get::<_, Option<i64>>(2)?.unwrap_or(0),
+                    lat: row.get::<_, Option<i64>>(3)?.unwrap_or(0),
+                    long: row.get::<_, Option<i64>>(4)?.unwrap_or(0),
+                    value: row.get(5)?,
+                })
+            },
+        )?;
+        for row in rows {
+            self.rows.push_back(row?);
+        }
+        if self.rows.len() < CHUNK_SIZE {
+            self.done = true;
+        }
+        if let Some(row) = self.rows.back() {
+            self.last_id = row.id;
+        }
+        Ok(!self.rows.is_empty())
+    }
+
+    // Next row that passes the filters, before decimation
+    fn next_row(&mut self) -> Option<Result<Row>> {
+        loop {
+            if self.rows.is_empty() {
+                match self.fetch() {
+                    Err(e) => return Some(Err(e)),
+                    Ok(false) => return None,
+                    Ok(true) => {}
+                }
+            }
+            let row = self.rows.pop_front()?;
+
+            if let Some(counter) = self.counter.as_mut() {
+                let at = from_millis(row.timestamp.unwrap_or(0));
+                let lap = replay::lap_number(&counter.add_point(
+                    row.lat as f64 / 10000000.0,
+                    row.long as f64 / 10000000.0,
+                    at,
+                ));
+                if let Some(laps) = &self.query.laps {
+                    if !laps.contains(&lap) {
+                        continue;
+                    }
+                }
+                let from = self.query.from.map(to_millis);
+                let to = self.query.to.map(to_millis);
+                if from.is_some() && (row.timestamp.is_none() || row.timestamp < from) {
+                    continue;
+                }
+                if to.is_some() && (row.timestamp.is_none() || row.timestamp > to) {
+                    continue;
+                }
+            }
+            return Some(Ok(row));
+        }
+    }
+
+    // Next row after decimation
+    fn next_decimated(&mut self) -> Option<Result<Row>> {
+        match self.query.decimation {
+            Decimation::None => self.next_row(),
+            Decimation::EveryNth(n) => loop {
+                let row = self.next_row()?;
+                self.seen += 1;
+                if (self.seen - 1).is_multiple_of(n.max(1)) {
+                    return Some(row);
+                }
+            },
+            Decimation::MinMax(size) => {
+                while self.pending.is_empty() {
+                    match self.next_row() {
+                        Some(Err(e)) => return Some(Err(e)),
+                        Some(Ok(row)) => {
+                            self.bucket.push(row);
+                            if self.bucket.len() >= size.max(1) {
+                                self.flush_bucket();
+                            }
+                        }
+                        None => {
+                            self.flush_bucket();
+                            break;
+                        }
+                    }
+                }
+                self.pending.pop_front().map(Ok)
+            }
+        }
+    }
+
+    // Hand out the slowest and fastest samples of the bucket, in the order
+    // they were logged
+    fn flush_bucket(&mut self) {
+        let bucket = std::mem::take(&mut self.bucket);
+        let min = bucket
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, r)| r.speed)
+            .map(|(i, _)| i);
+        let max = bucket
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, r)| r.speed)
+            .map(|(i, _)| i);
+        for (i, row) in bucket.into_iter().enumerate() {
+            if Some(i) == min || Some(i) == max {
+                self.pending.push_back(row);
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for SessionIter<'a> {
+    type Item = Result<RbMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        let row = match self.next_decimated()? {
+            Err(e) => return Some(Err(e)),
+            Ok(row) => row,
+        };
+        // Only the samples we hand out get parsed
+        Some(
+            serde_json::from_str(&row.value)
+                .map_err(|e| Error::FromSqlConversionFailure(5, Type::Text, Box::new(e))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rbmini::message::Datetime;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // A minute of driving back and forth across a start/finish line at
+    // latitude 2.5, one sample a second
+    fn logger() -> Logger {
+        let l = Logger::default();
+        for i in 0..60 {
+            let mut msg = RbMessage::new();
+            let lat = [1, 2, 3, 4, 3, 2][i % 6];
+            msg.update_coordinates(50000000, lat * 10000000);
+            msg.update_speed(i as i32 * 1000);
+            let datetime = Datetime {
+                year: 2023,
+                month: 3,
+                day: 4,
+                hour: 10,
+                minute: 0,
+                second: i as u8,
+            };
+            msg.update_datetime(datetime, 0);
+            l.write(1, &msg.to_json()).unwrap();
+        }
+        l
+    }
+
+    fn start() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1677924000)
+    }
+
+    #[test]
+    fn test_query_all() {
+        let l = logger();
+        let samples: Vec<RbMessage> = l
+            .query_session(1, SessionQuery::default())
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(samples.len(), 60);
+        assert!(l.query_session(2, SessionQuery::default()).next().is_none());
+    }
+
+    #[test]
+    fn test_query_time_range() {
+        let l = logger();
+        let query = SessionQuery {
+            from: Some(start() + Duration::from_secs(10)),
+            to: Some(start() + Duration::from_secs(19)),
+            ..Default::default()
+        };
+        let samples: Vec<RbMessage> = l.query_session(1, query).collect::<Result<_>>().unwrap();
+        assert_eq!(samples.len(), 10);
+        assert_eq!(samples[0].datetime().second, 10);
+    }
+
+    #[test]
+    fn test_query_laps() {
+        let l = logger();
+        let query = SessionQuery {
+            laps: Some(2..=3),
+            track: Some(Track::new("Test".to_string(), (2.5, 0.0), (2.5, 10.0))),
+            ..Default::default()
+        };
+        let samples: Vec<RbMessage> = l.query_session(1, query).collect::<Result<_>>().unwrap();
+        // Lap 2 starts on the way back down at sample 5, lap 4 at sample 11
+        assert_eq!(samples.len(), 6);
+        assert_eq!(samples[0].datetime().second, 5);
+    }
+
+    #[test]
+    fn test_query_laps_without_track() {
+        let l = logger();
+        let query = SessionQuery {
+            laps: Some(2..=3),
+            ..Default::default()
+        };
+        let mut samples = l.query_session(1, query);
+        assert!(samples.next().unwrap().is_err());
+        assert!(samples.next().is_none());
+    }
+
+    #[test]
+    fn test_query_every_nth() {
+        let l = logger();
+        let query = SessionQuery {
+            decimation: Decimation::EveryNth(10),
+            ..Default::default()
+        };
+        let samples: Vec<RbMessage> = l.query_session(1, query).collect::<Result<_>>().unwrap();
+        assert_eq!(samples.len(), 6);
+        assert_eq!(samples[1].datetime().second, 10);
+    }
+
+    #[test]
+    fn test_query_min_max() {
+        let l = logger();
+        let query = SessionQuery {
+            decimation: Decimation::MinMax(20),
+            ..Default::default()
+        };
+        let samples: Vec<RbMessage> = l.query_session(1, query).collect::<Result<_>>().unwrap();
+        let seconds: Vec<u8> = samples.iter().map(|s| s.datetime().second).collect();
+        assert_eq!(seconds, vec![0, 19, 20, 39, 40, 59]);
+    }
+
+    #[test]
+    fn test_query_many_chunks() {
+        let l = Logger::default();
+        for _ in 0..(CHUNK_SIZE * 2 + 1) {
+            l.write(1, &RbMessage::new().to_json()).unwrap();
+        }
+        assert_eq!(
+            l.query_session(1, SessionQuery::default()).count(),
+            CHUNK_SIZE * 2 + 1
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use rusqlite::named_params;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rbmini::message::{rb_checksum, try_decode_rb_message};
+
+use crate::Logger;
+
+impl Logger {
+    // Turn on or off keeping the raw frames from the device
+    pub fn set_keep_raw_frames(&mut self, keep: bool) {
+        self.keep_raw = keep;
+    }
+
+    pub fn keep_raw_frames(&self) -> bool {
+        self.keep_raw
+    }
+
+    // Store a frame exactly as it came off the device, does nothing unless
+    // keeping raw frames is turned on. These let us rebuild the telemetry
+    // if the decoder was wrong.
+    pub fn write_raw(
+        &self,
+        session_id: u64,
+        frame: &[u8],
+        received_at: SystemTime,
+    ) -> Result<(), String> {
+        if !self.keep_raw {
+            return Ok(());
+        }
+        let received_at = match received_at.duration_since(UNIX_EPOCH) {
+            Err(e) => return Err(format!("Bad receive time: {}", e)),
+            Ok(d) => d.as_millis() as i64,
+        };
+        let checksum_ok = frame.len() > 4 && rb_checksum(frame);
+        let mut stmt = match self.conn.prepare_cached(
+            "INSERT INTO raw_frames (session_id, received_at, checksum_ok, frame)
+             VALUES (:session_id, :received_at, :checksum_ok, :frame)",
+        ) {
+            Err(e) => return Err(format!("Failed to prepare insert: {}", e)),
+            Ok(stmt) => stmt,
+        };
+        if let Err(e) = stmt.execute(named_params! {
+            ":session_id": session_id,
+            ":received_at": received_at,
+            ":checksum_ok": checksum_ok,
+            ":frame": frame,
+        }) {
+            return Err(format!("Failed to write raw frame: {}", e));
+        }
+        Ok(())
+    }
+
+    // Sessions that have raw frames stored
+    pub fn get_raw_sessions(&self) -> rusqlite::Result<Vec<u64>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT session_id FROM raw_frames GROUP BY session_id")?;
+        let values = stmt.query_map([], |row| row.get(0))?;
+        values.collect()
+    }
+
+    // Throw away the decoded telemetry for a session and decode it again
+    // from the raw frames. Frames that failed their checksum are skipped.
+    // Returns the number of telemetry rows written.
+    pub fn redecode(&self, session_id: u64) -> Result<usize, Box<dyn Error>> {
+        let frames: Vec<Vec<u8>> = {
+            let mut stmt = self.conn.prepare(
+                "SELECT frame FROM raw_frames
+                 WHERE session_id=? AND checksum_ok=1 ORDER BY received_at, id",
+            )?;
+            let values = stmt.query_map([session_id], |row| row.get(0))?;
+            values.collect::<rusqlite::Result<_>>()?
+        };
+        if frames.is_empty() {
+            return Err(format!("No raw frames for session {}", session_id).into());
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM telemetry WHERE session_id=?", [session_id])?;
+        let mut written = 0;
+        for frame in frames.iter() {
+            if let Ok(msg) = try_decode_rb_message(frame) {
+                self.write(session_id, &msg.to_json())?;
+                written += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: [u8; 88] = [
+        0xB5, 0x62, 0xFF, 0x01, 0x50, 0x00, 0xA0, 0xE7, 0x0C, 0x07, 0xE6, 0x07, 0x01, 0x0A, 0x08,
+        0x33, 0x08, 0x37, 0x19, 0x00, 0x00, 0x00, 0x2A, 0xAD, 0x4D, 0x0E, 0x03, 0x01, 0xEA, 0x0B,
+        0xC6, 0x93, 0xE1, 0x0D, 0x3B, 0x37, 0x6F, 0x19, 0x61, 0x8C, 0x09, 0x00, 0x0F, 0x01, 0x09,
+        0x00, 0x9C, 0x03, 0x00, 0x00, 0x2C, 0x07, 0x00, 0x00, 0x23, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xD0, 0x00, 0x00, 0x00, 0x88, 0xA9, 0xDD, 0x00, 0x2C, 0x01, 0x00, 0x59, 0xFD,
+        0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00, 0xFC, 0xFF, 0x06, 0xDB,
+    ];
+
+    #[test]
+    fn test_write_raw_disabled() {
+        let l = Logger::default();
+        assert!(!l.keep_raw_frames());
+        l.write_raw(1, &FRAME, SystemTime::now()).unwrap();
+        assert!(l.get_raw_sessions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_redecode() {
+        let mut l = Logger::default();
+        l.set_keep_raw_frames(true);
+
+        let mut bad_frame = FRAME;
+        bad_frame[87] = 0xFF;
+        l.write_raw(1, &FRAME, SystemTime::now()).unwrap();
+        l.write_raw(1, &bad_frame, SystemTime::now()).unwrap();
+        l.write_raw(1, &FRAME, SystemTime::now()).unwrap();
+        assert_eq!(l.get_raw_sessions().unwrap(), vec![1]);
+
+        // Stale telemetry from a buggy decoder gets replaced
+        l.write(1, "{}").unwrap();
+        assert_eq!(l.redecode(1).unwrap(), 2);
+        let session = l.get_session(1).unwrap();
+        assert_eq!(session.len(), 2);
+        assert_eq!(session[0].gps_coordinates().latitude(), 42.6719035);
+
+        assert!(l.redecode(2).is_err());
+    }
+}
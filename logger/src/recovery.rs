@@ -0,0 +1,275 @@
+use rusqlite::Result;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use timer::Track;
+
+use crate::laps::{from_millis, motion, to_millis, LapRecord};
+use crate::query::SessionQuery;
+use crate::replay::LapCounter;
+use crate::Logger;
+
+impl Logger {
+    // Mark a session as being logged live, so we can tell if it gets cut short
+    pub fn start_session(&self, session_id: u64, at: SystemTime) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO sessions (session_id, started_at) VALUES (?1, ?2)
+             ON CONFLICT (session_id) DO UPDATE SET started_at=?2, ended_at=NULL",
+            (session_id, to_millis(at)),
+        )?;
+        Ok(())
+    }
+
+    pub fn end_session(&self, session_id: u64, at: SystemTime) -> Result<()> {
+        self.conn.execute(
+            "UPDATE sessions SET ended_at=? WHERE session_id=?",
+            (to_millis(at), session_id),
+        )?;
+        Ok(())
+    }
+
+    // When the session ended, None if it's still going or was cut short
+    pub fn session_end(&self, session_id: u64) -> Result<Option<SystemTime>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT ended_at FROM sessions WHERE session_id=?")?;
+        let mut values = stmt.query_map([session_id], |row| row.get::<_, Option<i64>>(0))?;
+        match values.next() {
+            Some(ended_at) => Ok(ended_at?.map(from_millis)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_recovered(&self, session_id: u64) -> Result<bool> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT recovered FROM sessions WHERE session_id=?")?;
+        let mut values = stmt.query_map([session_id], |row| row.get(0))?;
+        match values.next() {
+            Some(recovered) => recovered,
+            None => Ok(false),
+        }
+    }
+
+    // Sessions that were started but never ended
+    pub fn get_unfinished_sessions(&self) -> Result<Vec<u64>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT session_id FROM sessions
+             WHERE started_at IS NOT NULL AND ended_at IS NULL ORDER BY session_id",
+        )?;
+        let sessions = stmt.query_map([], |row| row.get(0))?;
+        sessions.collect()
+    }
+
+    // Close off every session that was cut short, most likely by losing
+    // power. Run this at startup before starting a new session. Returns the
+    // sessions that were recovered.
+    pub fn recover(&self, track: Option<&Track>) -> Result<Vec<u64>, Box<dyn Error>> {
+        let sessions = self.get_unfinished_sessions()?;
+        for session_id in &sessions {
+            self.recover_session(*session_id, track)?;
+        }
+        Ok(sessions)
+    }
+
+    // Ends the session at its last sample and works the laps out again from
+    // the telemetry, any laps written before the crash are replaced. Pass the
+    // same track as live timing, minimum lap time and all, to get the same
+    // laps back.
+    pub fn recover_session(
+        &self,
+        session_id: u64,
+        track: Option<&Track>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut laps = Vec::new();
+        let mut counter = track.map(LapCounter::new);
+        let mut last = None;
+        for sample in self.query_session(session_id, SessionQuery::default()) {
+            let sample = sample?;
+            // Samples without a time can't be placed, skip them
+            let at: SystemTime = match sample.utc() {
+                Some(utc) => utc.into(),
+                None => continue,
+            };
+            last = Some(at);
+
+            if let Some(counter) = counter.as_mut() {
+                let coords = sample.gps_coordinates();
+                counter.add_sample(coords.latitude(), coords.longitude(), at, motion(&sample));
+            }
+        }
+
+        // Times come from the timer, the same as timing it live, and the lap
+        // we were on when the power went is the in lap
+        if let Some(counter) = counter.as_mut() {
+            counter.finish();
+            for lap in counter.laps() {
+                laps.push(LapRecord {
+                    session_id,
+                    lap_type: *lap.number(),
+                    start: lap.start_time().unwrap_or(UNIX_EPOCH),
+                    end: lap.end_time().unwrap_or(UNIX_EPOCH),
+                    lap_time: lap.duration(),
+                    sectors: lap.sectors().to_vec(),
+                    valid: lap.is_valid(),
+                });
+            }
+        }
+
+        // Without any samples the best we can do is end it where it started
+        let started_at: i64 = self.conn.query_row(
+            "SELECT coalesce(started_at, session_id * 1000) FROM sessions WHERE session_id=?",
+            [session_id],
+            |row| row.get(0),
+        )?;
+        let end = last.unwrap_or_else(|| from_millis(started_at));
+
+        let tx = self.conn.unchecked_transaction()?;
+        // Keep the laps written live if there's no telemetry to replace
+        // them with
+        if track.is_some() && last.is_some() {
+            tx.execute("DELETE FROM laps WHERE session_id=?", [session_id])?;
+            for lap in &laps {
+                self.write_lap(lap)?;
+            }
+        }
+        tx.execute(
+            "UPDATE sessions SET ended_at=?, recovered=1 WHERE session_id=?",
+            (to_millis(end), session_id),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rbmini::message::{Datetime, RbMessage};
+    use std::time::Duration;
+    use timer::LapType;
+
+    // Driving back and forth across a start/finish line at latitude 2.5,
+    // one sample a second, then the power goes
+    fn crashed_logger() -> Logger {
+        let l = Logger::default();
+        l.start_session(1, UNIX_EPOCH + Duration::from_secs(1677924000))
+            .unwrap();
+        for i in 0..10 {
+            let mut msg = RbMessage::new();
+            let lat = [1, 2, 3, 4, 3, 2][i % 6];
+            msg.update_coordinates(50000000, lat * 10000000);
+            let datetime = Datetime {
+                year: 2023,
+                month: 3,
+                day: 4,
+                hour: 10,
+                minute: 0,
+                second: i as u8,
+            };
+            msg.update_datetime(datetime, 0);
+            l.write(1, &msg.to_json()).unwrap();
+        }
+        l
+    }
+
+    fn track() -> Track {
+        Track::new("Test".to_string(), (2.5, 0.0), (2.5, 10.0))
+    }
+
+    #[test]
+    fn test_unfinished_sessions() {
+        let l = crashed_logger();
+        l.start_session(2, SystemTime::now()).unwrap();
+        l.end_session(2, SystemTime::now()).unwrap();
+        l.star_session(3, true).unwrap();
+        assert_eq!(l.get_unfinished_sessions().unwrap(), vec![1]);
+        assert!(l.session_end(1).unwrap().is_none());
+        assert!(l.session_end(2).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_recover() {
+        let l = crashed_logger();
+        assert_eq!(l.recover(Some(&track())).unwrap(), vec![1]);
+        assert!(l.get_unfinished_sessions().unwrap().is_empty());
+        assert!(l.is_recovered(1).unwrap());
+        assert_eq!(
+            l.session_end(1).unwrap(),
+            Some(UNIX_EPOCH + Duration::from_secs(1677924009))
+        );
+
+        // Lines crossed at samples 2, 5 and 8, the lap in progress is the in lap
+        let laps = l.get_laps(1).unwrap();
+        let types: Vec<LapType> = laps.iter().map(|l| l.lap_type).collect();
+        let expected = vec![LapType::Out, LapType::Lap(1), LapType::Lap(2), LapType::In];
+        assert_eq!(types, expected);
+        assert_eq!(laps[1].lap_time, Duration::from_secs(3));
+        assert_eq!(laps[3].lap_time, Duration::from_millis(1500));
+
+        // Nothing left to do the second time around
+        assert!(l.recover(Some(&track())).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recover_replaces_laps() {
+        let l = crashed_logger();
+        let lap = LapRecord {
+            session_id: 1,
+            lap_type: LapType::Out,
+            start: UNIX_EPOCH,
+            end: UNIX_EPOCH,
+            lap_time: Duration::ZERO,
+            sectors: vec![],
+            valid: true,
+        };
+        l.write_lap(&lap).unwrap();
+        l.recover(Some(&track())).unwrap();
+        assert_eq!(l.get_laps(1).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_recover_min_lap_time() {
+        let l = crashed_logger();
+        let mut track = track();
+        track.set_min_lap_time(Duration::from_secs(4));
+        l.recover(Some(&track)).unwrap();
+        // The crossing at sample 5 is too soon after the one at sample 2
+        let laps = l.get_laps(1).unwrap();
+        let types: Vec<LapType> = laps.iter().map(|l| l.lap_type).collect();
+        assert_eq!(types, vec![LapType::Out, LapType::Lap(1), LapType::In]);
+        assert_eq!(laps[1].lap_time, Duration::from_secs(6));
+    }
+
+    #[test]
+    fn test_recover_empty_session() {
+        let l = Logger::default();
+        l.start_session(5, UNIX_EPOCH + Duration::from_secs(5))
+            .unwrap();
+        l.recover(None).unwrap();
+        assert_eq!(
+            l.session_end(5).unwrap(),
+            Some(UNIX_EPOCH + Duration::from_secs(5))
+        );
+        assert!(l.get_laps(5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recover_keeps_laps_without_telemetry() {
+        let l = Logger::default();
+        l.start_session(5, UNIX_EPOCH + Duration::from_secs(5))
+            .unwrap();
+        let lap = LapRecord {
+            session_id: 5,
+            lap_type: LapType::Out,
+            start: UNIX_EPOCH + Duration::from_secs(5),
+            end: UNIX_EPOCH + Duration::from_secs(65),
+            lap_time: Duration::from_secs(60),
+            sectors: vec![],
+            valid: true,
+        };
+        l.write_lap(&lap).unwrap();
+        l.recover(Some(&track())).unwrap();
+        assert_eq!(l.get_laps(5).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,158 @@
+use rbmini::message::RbMessage;
+use std::time::{SystemTime, UNIX_EPOCH};
+use timer::{Lap, LapType, Motion, Session, Track};
+
+// Replays logged telemetry through the lap timer to work out which lap each
+// sample belongs to.
+//
+// The sample that crosses the start/finish line is the first sample of the
+// next lap, same as the timer does it.
+pub(crate) fn lap_types(samples: &[RbMessage], track: &Track) -> Vec<LapType> {
+    let mut counter = LapCounter::new(track);
+    samples
+        .iter()
+        .map(|sample| {
+            let coords = sample.gps_coordinates();
+            let at = sample.utc().map(SystemTime::from).unwrap_or(UNIX_EPOCH);
+            counter.add_point(coords.latitude(), coords.longitude(), at)
+        })
+        .collect()
+}
+
+// Same as lap_types as numbers, the out and in laps are lap 0
+pub(crate) fn lap_numbers(samples: &[RbMessage], track: &Track) -> Vec<u16> {
+    lap_types(samples, track).iter().map(lap_number).collect()
+}
+
+// Where and when the timer saw start/finish crossed, interpolated between
+// the samples either side of the line. index is the sample after the line.
+pub(crate) struct Crossing {
+    pub(crate) index: usize,
+    pub(crate) at: SystemTime,
+    pub(crate) lat: f64,
+    pub(crate) long: f64,
+}
+
+// Every start/finish crossing in the samples
+pub(crate) fn crossings(samples: &[RbMessage], track: &Track) -> Vec<Crossing> {
+    let mut counter = LapCounter::new(track);
+    let mut crossings = Vec::new();
+    for (index, sample) in samples.iter().enumerate() {
+        let coords = sample.gps_coordinates();
+        let at = sample.utc().map(SystemTime::from).unwrap_or(UNIX_EPOCH);
+        counter.add_point(coords.latitude(), coords.longitude(), at);
+        if let Some((at, (lat, long))) = counter.crossed {
+            crossings.push(Crossing {
+                index,
+                at,
+                lat,
+                long,
+            });
+        }
+    }
+    crossings
+}
+
+// Same as lap_numbers, a sample at a time
+pub(crate) struct LapCounter {
+    session: Session,
+    lap: Lap,
+    crossed: Option<(SystemTime, (f64, f64))>, // Start/finish, by the last point
+}
+
+impl LapCounter {
+    pub(crate) fn new(track: &Track) -> Self {
+        let session = Session::new(track.clone());
+        let lap = session.start();
+        LapCounter {
+            session,
+            lap,
+            crossed: None,
+        }
+    }
+
+    // Returns the lap the point belongs to
+    pub(crate) fn add_point(&mut self, lat: f64, long: f64, at: SystemTime) -> LapType {
+        self.lap.add_point(lat, long, at);
+        self.update()
+    }
+
+    // Same as add_point, with the speed and g for the lap metrics
+    pub(crate) fn add_sample(
+        &mut self,
+        lat: f64,
+        long: f64,
+        at: SystemTime,
+        motion: Motion,
+    ) -> LapType {
+        self.lap.add_sample(lat, long, at, motion);
+        self.update()
+    }
+
+    fn update(&mut self) -> LapType {
+        self.session.update_pits(&mut self.lap);
+        self.session.split(&mut self.lap);
+        self.crossed = None;
+        if self.session.is_lap_complete(&self.lap) {
+            let lap = std::mem::replace(&mut self.lap, Lap::new(LapType::Out));
+            self.lap = self.session.add_lap(lap);
+            self.crossed = self.lap.start_time().zip(self.lap.start_coord());
+        }
+        *self.lap.number()
+    }
+
+    // Close off the lap in progress at the last point, the same as stopping
+    // a session live
+    pub(crate) fn finish(&mut self) {
+        let lap = std::mem::replace(&mut self.lap, Lap::new(LapType::Out));
+        self.session.finish(lap);
+    }
+
+    // Every completed lap so far
+    pub(crate) fn laps(&self) -> &[Lap] {
+        self.session.laps()
+    }
+}
+
+pub(crate) fn lap_number(lap_type: &LapType) -> u16 {
+    match lap_type {
+        LapType::Out => 0,
+        LapType::Lap(num) => *num,
+        LapType::In => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples() -> Vec<RbMessage> {
+        let mut samples = Vec::new();
+        for lat in [1, 2, 3, 4, 3, 2, 1, 2, 3] {
+            let mut msg = RbMessage::new();
+            msg.update_coordinates(50000000, lat * 10000000);
+            samples.push(msg);
+        }
+        samples
+    }
+
+    #[test]
+    fn test_lap_numbers() {
+        let track = Track::new("Test Track".to_string(), (2.5, 0.0), (2.5, 10.0));
+        assert_eq!(
+            lap_numbers(&samples(), &track),
+            vec![0, 0, 1, 1, 1, 2, 2, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_min_lap_time() {
+        // None of the samples have a time, so no lap is long enough
+        let mut track = Track::new("Test Track".to_string(), (2.5, 0.0), (2.5, 10.0));
+        track.set_min_lap_time(std::time::Duration::from_secs(20));
+        assert_eq!(
+            lap_numbers(&samples(), &track),
+            vec![0, 0, 1, 1, 1, 1, 1, 1, 1]
+        );
+    }
+}
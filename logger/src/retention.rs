@@ -0,0 +1,267 @@
+use rusqlite::Result;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::Logger;
+
+// How much of the SD card the logger is allowed to use
+pub struct RetentionPolicy {
+    pub max_size: Option<u64>,     // Most bytes of logging to keep
+    pub max_age: Option<Duration>, // Sessions older than this are pruned
+    pub keep_starred: bool,        // Starred sessions are never pruned
+    pub vacuum_pages: u32,         // Pages to give back per vacuum, 0 for all of them
+    pub min_free_space: u64,       // Warn when the disk has less than this many bytes free
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_size: Some(4 * 1024 * 1024 * 1024),
+            max_age: None,
+            keep_starred: true,
+            vacuum_pages: 1000,
+            min_free_space: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl Logger {
+    pub fn star_session(&self, session_id: u64, starred: bool) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO sessions (session_id, starred) VALUES (?1, ?2)
+             ON CONFLICT (session_id) DO UPDATE SET starred=?2",
+            (session_id, starred),
+        )?;
+        Ok(())
+    }
+
+    pub fn is_starred(&self, session_id: u64) -> Result<bool> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT starred FROM sessions WHERE session_id=?")?;
+        let mut values = stmt.query_map([session_id], |row| row.get(0))?;
+        match values.next() {
+            Some(starred) => starred,
+            None => Ok(false),
+        }
+    }
+
+    // Remove everything logged for a session
+    pub fn delete_session(&self, session_id: u64) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM telemetry WHERE session_id=?", [session_id])?;
+        tx.execute("DELETE FROM raw_frames WHERE session_id=?", [session_id])?;
+        tx.execute("DELETE FROM laps WHERE session_id=?", [session_id])?;
+        tx.execute("DELETE FROM performance WHERE session_id=?", [session_id])?;
+        tx.execute("DELETE FROM sessions WHERE session_id=?", [session_id])?;
+        tx.commit()
+    }
+
+    // Bytes in use by the database, not counting free pages waiting to be vacuumed
+    pub fn size(&self) -> Result<u64> {
+        let page_size: u64 = self
+            .conn
+            .query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        let pages: u64 = self
+            .conn
+            .query_row("PRAGMA page_count", [], |row| row.get(0))?;
+        let free: u64 = self
+            .conn
+            .query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+        Ok((pages - free) * page_size)
+    }
+
+    // Bytes free on the disk holding the database, None for in memory databases
+    pub fn free_space(&self) -> Option<u64> {
+        if self.path.as_os_str().is_empty() {
+            return None;
+        }
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs2::available_space(dir).ok()
+    }
+
+    // Give free pages back to the filesystem, a few at a time so we don't
+    // stall logging
+    pub fn vacuum(&self, pages: u32) -> Result<()> {
+        let sql = match pages {
+            0 => "PRAGMA incremental_vacuum".to_string(),
+            pages => format!("PRAGMA incremental_vacuum({})", pages),
+        };
+        let mut stmt = self.conn.prepare(&sql)?;
+        let mut rows = stmt.query([])?;
+        while rows.next()?.is_some() {}
+        Ok(())
+    }
+
+    // Rewrite the whole database so free pages can be vacuumed a bit at a
+    // time from then on. Databases from before incremental vacuuming need
+    // this once. It takes a while on a big log and needs about as much free
+    // disk again as the database takes up, so it's never done at startup.
+    pub fn compact(&self) -> Result<()> {
+        self.conn
+            .execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM")
+    }
+
+    // Whether free pages can be given back with vacuum
+    pub fn is_incremental(&self) -> Result<bool> {
+        let mode: u32 = self
+            .conn
+            .query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+        Ok(mode == 2)
+    }
+
+    // Delete sessions the policy says we can't keep, oldest first. The
+    // newest session is never pruned, it's probably the one being logged.
+    // Returns the sessions that were deleted.
+    pub fn prune(&self, policy: &RetentionPolicy, now: SystemTime) -> Result<Vec<u64>> {
+        let mut candidates = Vec::new();
+        let mut sessions = self.get_sessions()?;
+        sessions.pop();
+        for session_id in sessions {
+            if policy.keep_starred && self.is_starred(session_id)? {
+                continue;
+            }
+            candidates.push(session_id);
+        }
+
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut pruned = Vec::new();
+        for session_id in candidates {
+            // Session ids are the start time of the session in seconds
+            let too_old = match policy.max_age {
+                Some(max_age) => now.saturating_sub(session_id) > max_age.as_secs(),
+                None => false,
+            };
+            let too_big = match policy.max_size {
+                Some(max_size) => self.size()? > max_size,
+                None => false,
+            };
+            if !too_old && !too_big {
+                break;
+            }
+            self.delete_session(session_id)?;
+            pruned.push(session_id);
+        }
+
+        if !pruned.is_empty() {
+            self.vacuum(policy.vacuum_pages)?;
+        }
+        Ok(pruned)
+    }
+
+    // Something to tell the driver if we're running out of room
+    pub fn storage_warning(&self, policy: &RetentionPolicy) -> Option<String> {
+        if let Some(free) = self.free_space() {
+            if free < policy.min_free_space {
+                return Some(format!("Disk nearly full, {} MB free", free / 1024 / 1024));
+            }
+        }
+        if let (Some(max_size), Ok(size)) = (policy.max_size, self.size()) {
+            if size > max_size / 10 * 9 {
+                return Some(format!(
+                    "Log nearly full, {} of {} MB used",
+                    size / 1024 / 1024,
+                    max_size / 1024 / 1024
+                ));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logger_with_sessions(sessions: &[u64]) -> Logger {
+        let l = Logger::default();
+        for session_id in sessions {
+            for _ in 0..100 {
+                l.write(*session_id, &"x".repeat(1000)).unwrap();
+            }
+        }
+        l
+    }
+
+    #[test]
+    fn test_star_session() {
+        let l = Logger::default();
+        assert!(!l.is_starred(1).unwrap());
+        l.star_session(1, true).unwrap();
+        assert!(l.is_starred(1).unwrap());
+        l.star_session(1, false).unwrap();
+        assert!(!l.is_starred(1).unwrap());
+    }
+
+    #[test]
+    fn test_delete_session() {
+        let l = logger_with_sessions(&[1, 2]);
+        l.delete_session(1).unwrap();
+        assert_eq!(l.get_sessions().unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn test_prune_by_age() {
+        let l = logger_with_sessions(&[1000, 2000, 3000, 4000]);
+        l.star_session(1000, true).unwrap();
+        let policy = RetentionPolicy {
+            max_size: None,
+            max_age: Some(Duration::from_secs(1500)),
+            ..Default::default()
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(4000);
+        assert_eq!(l.prune(&policy, now).unwrap(), vec![2000]);
+        assert_eq!(l.get_sessions().unwrap(), vec![1000, 3000, 4000]);
+    }
+
+    #[test]
+    fn test_prune_by_size() {
+        let l = logger_with_sessions(&[1, 2, 3, 4]);
+        let size = l.size().unwrap();
+        let policy = RetentionPolicy {
+            max_size: Some(size / 2),
+            keep_starred: false,
+            ..Default::default()
+        };
+        let pruned = l.prune(&policy, SystemTime::now()).unwrap();
+        assert!(!pruned.is_empty());
+        assert_eq!(pruned[0], 1);
+        assert!(l.size().unwrap() <= size / 2);
+        assert!(l.get_sessions().unwrap().contains(&4));
+    }
+
+    #[test]
+    fn test_storage_warning() {
+        let l = logger_with_sessions(&[1]);
+        assert!(l.free_space().is_none());
+        let policy = RetentionPolicy {
+            max_size: Some(l.size().unwrap()),
+            ..Default::default()
+        };
+        assert!(l.storage_warning(&policy).is_some());
+        let policy = RetentionPolicy::default();
+        assert!(l.storage_warning(&policy).is_none());
+    }
+
+    #[test]
+    fn test_compact() {
+        assert!(Logger::default().is_incremental().unwrap());
+
+        // A database from before incremental vacuuming
+        let path = "/tmp/openlaps_test_compact.db";
+        let _ = std::fs::remove_file(path);
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute_batch("CREATE TABLE old (id INTEGER PRIMARY KEY)")
+            .unwrap();
+        drop(conn);
+
+        let l = Logger::new(path);
+        assert!(!l.is_incremental().unwrap());
+        l.compact().unwrap();
+        assert!(l.is_incremental().unwrap());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,256 @@
+use rusqlite::{OptionalExtension, Result};
+use std::time::{Duration, SystemTime};
+
+use crate::laps::from_millis;
+use crate::Logger;
+
+// The headline numbers for a session, cheap enough to work out for every
+// session in the database
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionSummary {
+    pub session_id: u64,
+    pub name: Option<String>,
+    pub samples: u64,
+    pub start: Option<SystemTime>, // First and last sample times
+    pub end: Option<SystemTime>,
+    pub laps: usize, // Timed laps, not counting out and in laps
+    pub best_lap: Option<Duration>,
+    pub starred: bool,
+    pub recovered: bool,
+}
+
+impl Logger {
+    pub fn session_summary(&self, session_id: u64) -> Result<SessionSummary> {
+        let (samples, start, end): (u64, Option<i64>, Option<i64>) = self.conn.query_row(
+            "SELECT count(*), min(timestamp), max(timestamp) FROM telemetry WHERE session_id=?",
+            [session_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        let (laps, best_lap): (usize, Option<u64>) = self.conn.query_row(
+            "SELECT count(*), min(iif(valid, lap_time, NULL)) FROM laps
+             WHERE session_id=? AND lap_type='lap'",
+            [session_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let (name, starred, recovered) = self
+            .conn
+            .query_row(
+                "SELECT name, starred, recovered FROM sessions WHERE session_id=?",
+                [session_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?
+            .unwrap_or((None, false, false));
+        Ok(SessionSummary {
+            session_id,
+            name,
+            samples,
+            start: start.map(from_millis),
+            end: end.map(from_millis),
+            laps,
+            best_lap: best_lap.map(Duration::from_millis),
+            starred,
+            recovered,
+        })
+    }
+
+    pub fn rename_session(&self, session_id: u64, name: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO sessions (session_id, name) VALUES (?1, ?2)
+             ON CONFLICT (session_id) DO UPDATE SET name=?2",
+            (session_id, name),
+        )?;
+        Ok(())
+    }
+
+    // Fold one session into another, e.g. when a stop in the pits split a
+    // session in two. The merged session keeps the name and star of the
+    // session merged into, and its timed laps are numbered again in the
+    // order they were driven.
+    pub fn merge_sessions(&self, into: u64, from: u64) -> Result<()> {
+        if into == from {
+            return Ok(());
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE telemetry SET session_id=? WHERE session_id=?",
+            (into, from),
+        )?;
+        tx.execute(
+            "UPDATE raw_frames SET session_id=? WHERE session_id=?",
+            (into, from),
+        )?;
+        tx.execute(
+            "UPDATE laps SET session_id=? WHERE session_id=?",
+            (into, from),
+        )?;
+        tx.execute(
+            "UPDATE laps SET number=(
+                SELECT number FROM (
+                    SELECT id, row_number() OVER (ORDER BY start_time, id) AS number
+                    FROM laps WHERE session_id=?1 AND lap_type='lap'
+                ) AS numbered WHERE numbered.id=laps.id)
+             WHERE session_id=?1 AND lap_type='lap'",
+            [into],
+        )?;
+        tx.execute(
+            "UPDATE performance SET session_id=? WHERE session_id=?",
+            (into, from),
+        )?;
+        tx.execute("DELETE FROM sessions WHERE session_id=?", [from])?;
+        tx.commit()
+    }
+
+    // Problems found with the database, empty if everything looks fine
+    pub fn check_integrity(&self) -> Result<Vec<String>> {
+        let mut problems = Vec::new();
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        for row in rows {
+            let row = row?;
+            if row != "ok" {
+                problems.push(row);
+            }
+        }
+
+        let bad_rows: u64 = self.conn.query_row(
+            "SELECT count(*) FROM telemetry WHERE NOT json_valid(value)",
+            [],
+            |row| row.get(0),
+        )?;
+        if bad_rows > 0 {
+            problems.push(format!("{} telemetry rows are not valid JSON", bad_rows));
+        }
+
+        let orphans: u64 = self.conn.query_row(
+            "SELECT count(*) FROM laps
+             WHERE session_id NOT IN (SELECT session_id FROM telemetry)",
+            [],
+            |row| row.get(0),
+        )?;
+        if orphans > 0 {
+            problems.push(format!("{} laps have no telemetry", orphans));
+        }
+        Ok(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::laps::LapRecord;
+    use rbmini::message::{Datetime, RbMessage};
+    use std::time::UNIX_EPOCH;
+    use timer::LapType;
+
+    fn sample(second: u8) -> String {
+        let mut msg = RbMessage::new();
+        let datetime = Datetime {
+            year: 2023,
+            month: 3,
+            day: 4,
+            hour: 10,
+            minute: 0,
+            second,
+        };
+        msg.update_datetime(datetime, 0);
+        msg.to_json()
+    }
+
+    fn lap(session_id: u64, lap_type: LapType, lap_time: u64) -> LapRecord {
+        LapRecord {
+            session_id,
+            lap_type,
+            start: UNIX_EPOCH,
+            end: UNIX_EPOCH + Duration::from_secs(lap_time),
+            lap_time: Duration::from_secs(lap_time),
+            sectors: vec![],
+            valid: true,
+        }
+    }
+
+    #[test]
+    fn test_session_summary() {
+        let l = Logger::default();
+        for second in 0..10 {
+            l.write(1, &sample(second)).unwrap();
+        }
+        l.write_lap(&lap(1, LapType::Out, 100)).unwrap();
+        l.write_lap(&lap(1, LapType::Lap(1), 95)).unwrap();
+        l.write_lap(&lap(1, LapType::Lap(2), 93)).unwrap();
+        l.rename_session(1, "Morning").unwrap();
+
+        let summary = l.session_summary(1).unwrap();
+        assert_eq!(summary.name, Some("Morning".to_string()));
+        assert_eq!(summary.samples, 10);
+        assert_eq!(
+            summary
+                .end
+                .unwrap()
+                .duration_since(summary.start.unwrap())
+                .ok(),
+            Some(Duration::from_secs(9))
+        );
+        assert_eq!(summary.laps, 2);
+        assert_eq!(summary.best_lap, Some(Duration::from_secs(93)));
+        assert!(!summary.starred);
+
+        let empty = l.session_summary(2).unwrap();
+        assert_eq!(empty.samples, 0);
+        assert_eq!(empty.name, None);
+    }
+
+    #[test]
+    fn test_merge_sessions() {
+        let l = Logger::default();
+        l.write(1, &sample(0)).unwrap();
+        l.write(2, &sample(1)).unwrap();
+        l.write_lap(&lap(2, LapType::Lap(1), 95)).unwrap();
+        l.rename_session(2, "Afternoon").unwrap();
+        l.merge_sessions(1, 2).unwrap();
+
+        assert_eq!(l.get_sessions().unwrap(), vec![1]);
+        assert_eq!(l.get_session(1).unwrap().len(), 2);
+        assert_eq!(l.get_laps(1).unwrap().len(), 1);
+        assert_eq!(l.session_summary(2).unwrap().name, None);
+    }
+
+    #[test]
+    fn test_merge_sessions_renumbers_laps() {
+        // Both sessions have a lap 1, the second session was driven later
+        let later = |mut lap: LapRecord| {
+            lap.start += Duration::from_secs(1000);
+            lap.end += Duration::from_secs(1000);
+            lap
+        };
+        let l = Logger::default();
+        l.write_lap(&lap(1, LapType::Out, 100)).unwrap();
+        l.write_lap(&lap(1, LapType::Lap(1), 95)).unwrap();
+        l.write_lap(&lap(1, LapType::In, 110)).unwrap();
+        l.write_lap(&later(lap(2, LapType::Out, 100))).unwrap();
+        l.write_lap(&later(lap(2, LapType::Lap(1), 93))).unwrap();
+        l.write_lap(&later(lap(2, LapType::Lap(2), 94))).unwrap();
+        l.merge_sessions(1, 2).unwrap();
+
+        let types: Vec<LapType> = l.get_laps(1).unwrap().iter().map(|l| l.lap_type).collect();
+        let expected = vec![
+            LapType::Out,
+            LapType::Lap(1),
+            LapType::In,
+            LapType::Out,
+            LapType::Lap(2),
+            LapType::Lap(3),
+        ];
+        assert_eq!(types, expected);
+    }
+
+    #[test]
+    fn test_check_integrity() {
+        let l = Logger::default();
+        l.write(1, &sample(0)).unwrap();
+        assert!(l.check_integrity().unwrap().is_empty());
+        l.write(1, "not json").unwrap();
+        l.write_lap(&lap(2, LapType::Lap(1), 95)).unwrap();
+        assert_eq!(l.check_integrity().unwrap().len(), 2);
+    }
+}
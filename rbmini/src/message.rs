@@ -1,4 +1,6 @@
 use bincode::deserialize;
+use chrono::DateTime;
+use chrono::Duration;
 use chrono::LocalResult;
 use chrono::TimeZone;
 use chrono::Utc;
@@ -393,12 +395,80 @@ impl RbMessage {
         self.coordinates.latitude = latitude;
     }
 
+    // Not normally used, aids in testing
+    pub fn update_datetime(&mut self, datetime: Datetime, nanoseconds: i32) {
+        self.datetime = datetime;
+        self.nanoseconds = nanoseconds;
+    }
+
+    // Setters for building messages from other sources (e.g. importing another
+    // logger's data). Units are the same as the device sends.
+
+    pub fn update_fix(&mut self, valid: bool) {
+        if valid {
+            self.fix_status = FixStatus::Fix3D as u8;
+            self.fix_status_flags |= 1;
+            self.validity |= 0b111; // valid date, valid time, fully resolved
+        } else {
+            self.fix_status = FixStatus::NoFix as u8;
+            self.fix_status_flags &= !1;
+        }
+    }
+
+    pub fn update_speed(&mut self, speed: i32) {
+        self.speed = speed;
+    }
+
+    pub fn update_heading(&mut self, heading: i32) {
+        self.heading = heading;
+    }
+
+    pub fn update_altitude(&mut self, altitude: i32) {
+        self.msl_altitude = altitude;
+    }
+
+    pub fn update_horiz_accuracy(&mut self, accuracy: u32) {
+        self.horizontal_accuracy = accuracy;
+    }
+
+    pub fn update_satelites(&mut self, satelites: u8) {
+        self.number_of_svs = satelites;
+    }
+
+    pub fn update_g_forces(&mut self, x: i16, y: i16, z: i16) {
+        self.g_force_x = x;
+        self.g_force_y = y;
+        self.g_force_z = z;
+    }
+
+    pub fn update_rot_rates(&mut self, x: i16, y: i16, z: i16) {
+        self.rot_rate_x = x;
+        self.rot_rate_y = y;
+        self.rot_rate_z = z;
+    }
+
     // Getters
 
     pub fn datetime(&self) -> Datetime {
         self.datetime
     }
 
+    // UTC timestamp of the sample including the (signed) nanoseconds,
+    // None if the receiver hasn't given us a usable date and time
+    pub fn utc(&self) -> Option<DateTime<Utc>> {
+        match Utc.with_ymd_and_hms(
+            self.datetime.year.into(),
+            self.datetime.month.into(),
+            self.datetime.day.into(),
+            self.datetime.hour.into(),
+            self.datetime.minute.into(),
+            self.datetime.second.into(),
+        ) {
+            LocalResult::Single(dt) => Some(dt + Duration::nanoseconds(self.nanoseconds.into())),
+            _ => None,
+        }
+    }
+
     pub fn satelites(&self) -> u8 {
         self.number_of_svs
     }
@@ -491,6 +561,15 @@ pub fn decode_rb_message(raw: &[u8]) -> RbMessage {
     message
 }
 
+// Same as decode_rb_message but a short or garbled frame is an error
+// instead of a panic
+pub fn try_decode_rb_message(raw: &[u8]) -> Result<RbMessage, String> {
+    match deserialize(raw) {
+        Err(e) => Err(format!("Failed to decode message: {}", e)),
+        Ok(message) => Ok(message),
+    }
+}
+
 /*
 The 2-byte checksum is calculated over the packet’s contents - the message class
 and ID bytes, the payload length bytes, and the payload itself. The formula is:
@@ -616,6 +695,22 @@ mod tests {
         assert_eq!(message.checksum.value, 0xDB06);
     }
 
+    #[test]
+    fn test_try_decode_rb_message() {
+        let raw = [
+            0xB5, 0x62, 0xFF, 0x01, 0x50, 0x00, 0xA0, 0xE7, 0x0C, 0x07, 0xE6, 0x07, 0x01, 0x0A,
+            0x08, 0x33, 0x08, 0x37, 0x19, 0x00, 0x00, 0x00, 0x2A, 0xAD, 0x4D, 0x0E, 0x03, 0x01,
+            0xEA, 0x0B, 0xC6, 0x93, 0xE1, 0x0D, 0x3B, 0x37, 0x6F, 0x19, 0x61, 0x8C, 0x09, 0x00,
+            0x0F, 0x01, 0x09, 0x00, 0x9C, 0x03, 0x00, 0x00, 0x2C, 0x07, 0x00, 0x00, 0x23, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xD0, 0x00, 0x00, 0x00, 0x88, 0xA9, 0xDD, 0x00,
+            0x2C, 0x01, 0x00, 0x59, 0xFD, 0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00,
+            0xFC, 0xFF, 0x06, 0xDB,
+        ];
+        let message = message::try_decode_rb_message(&raw).unwrap();
+        assert_eq!(message.itow, 118286240);
+        assert!(message::try_decode_rb_message(&raw[..20]).is_err());
+    }
+
     #[test]
     fn test_valid_date() {
         let raw = [
@@ -725,4 +820,37 @@ mod tests {
         let message = message::decode_rb_message(&raw);
         assert_eq!(message.speed(), 0.126);
     }
+
+    #[test]
+    fn test_utc() {
+        let raw = [
+            0xB5, 0x62, 0xFF, 0x01, 0x50, 0x00, 0xA0, 0xE7, 0x0C, 0x07, 0xE6, 0x07, 0x01, 0x0A,
+            0x08, 0x33, 0x08, 0x37, 0x19, 0x00, 0x00, 0x00, 0x2A, 0xAD, 0x4D, 0x0E, 0x03, 0x01,
+            0xEA, 0x0B, 0xC6, 0x93, 0xE1, 0x0D, 0x3B, 0x37, 0x6F, 0x19, 0x61, 0x8C, 0x09, 0x00,
+            0x0F, 0x01, 0x09, 0x00, 0x9C, 0x03, 0x00, 0x00, 0x2C, 0x07, 0x00, 0x00, 0x23, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xD0, 0x00, 0x00, 0x00, 0x88, 0xA9, 0xDD, 0x00,
+            0x2C, 0x01, 0x00, 0x59, 0xFD, 0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00,
+            0xFC, 0xFF, 0x06, 0xDB,
+        ];
+        let message = message::decode_rb_message(&raw);
+        let utc = message.utc().unwrap();
+        assert_eq!(utc.timestamp(), 1641804668);
+        assert_eq!(utc.timestamp_subsec_nanos(), 239971626);
+
+        // The default message has no date to speak of
+        assert!(RbMessage::new().utc().is_none());
+    }
+
+    #[test]
+    fn test_update_fix() {
+        let mut message = RbMessage::new();
+        assert!(!message.is_valid_fix());
+        message.update_fix(true);
+        assert!(message.is_valid_fix());
+        assert!(message.is_valid_date());
+        assert!(message.is_valid_time());
+        assert_eq!(message.fix_status, 3);
+        message.update_fix(false);
+        assert!(!message.is_valid_fix());
+    }
 }
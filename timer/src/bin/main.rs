@@ -36,8 +36,11 @@ fn main() {
         (2.1, 1.1),
     ];
 
-    for point in points {
-        let p = lap.add_point(point.0, point.1);
+    // Pretend the points were sampled at 25Hz
+    let start = std::time::SystemTime::now();
+    for (i, point) in points.iter().enumerate() {
+        let at = start + std::time::Duration::from_millis(i as u64 * 40);
+        let p = lap.add_point(point.0, point.1, at);
         println!("Added point {:?} at {:?}", p.coord(), p.at());
         if session.is_lap_complete(&lap) {
             println!("Lap finished!");
@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{Lap, LapType};
+
+// A completed lap to compare the current lap against. Laps are lined up by
+// distance rather than time, so how far ahead or behind we are is the
+// difference in time to get to the same place on track.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reference {
+    lap_type: LapType,
+    distances: Vec<f64>,    // Metres from the start of the lap, increasing
+    elapsed: Vec<Duration>, // Time into the lap at each distance
+    lap_time: Duration,
+}
+
+impl Reference {
+    // None if the lap isn't finished or didn't go anywhere
+    pub fn from_lap(lap: &Lap) -> Option<Reference> {
+        let start = lap.start_time()?;
+        lap.end_time()?;
+        let mut distances = vec![0.0];
+        let mut elapsed = vec![Duration::ZERO];
+        for point in &lap.points {
+            // Sitting still adds nothing to line up against
+            if distances.last().is_some_and(|d| point.distance <= *d) {
+                continue;
+            }
+            distances.push(point.distance);
+            elapsed.push(point.time.duration_since(start).unwrap_or_default());
+        }
+        // The last point went to the next lap, finish at the line instead
+        if distances.last().is_some_and(|d| lap.distance() > *d) {
+            distances.push(lap.distance());
+            elapsed.push(lap.duration());
+        }
+        if distances.len() < 2 {
+            return None;
+        }
+        Some(Reference {
+            lap_type: lap.lap_type,
+            distances,
+            elapsed,
+            lap_time: lap.duration(),
+        })
+    }
+
+    pub fn lap_type(&self) -> LapType {
+        self.lap_type
+    }
+
+    pub fn lap_time(&self) -> Duration {
+        self.lap_time
+    }
+
+    // How long the reference lap took to get this far, None once we've gone
+    // further than it did
+    pub fn elapsed_at(&self, distance: f64) -> Option<Duration> {
+        let last = self.distances.len() - 1;
+        if distance > self.distances[last] {
+            return None;
+        }
+        let i = match self.distances.partition_point(|d| *d < distance) {
+            0 => return Some(self.elapsed[0]),
+            i => i,
+        };
+        let (d0, d1) = (self.distances[i - 1], self.distances[i]);
+        let (t0, t1) = (self.elapsed[i - 1], self.elapsed[i]);
+        let fraction = (distance - d0) / (d1 - d0);
+        Some(t0 + (t1 - t0).mul_f64(fraction))
+    }
+
+    // Seconds behind the reference at the lap's latest point, negative when
+    // ahead
+    pub fn delta(&self, lap: &Lap) -> Option<f64> {
+        let reference = self.elapsed_at(lap.distance())?;
+        Some(lap.time().as_secs_f64() - reference.as_secs_f64())
+    }
+
+    // What the lap will come in at if we keep the same gap to the reference
+    pub fn predicted_lap_time(&self, lap: &Lap) -> Option<Duration> {
+        let predicted = self.lap_time.as_secs_f64() + self.delta(lap)?;
+        Some(Duration::from_secs_f64(predicted.max(0.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    // A degree of latitude is about 111km, so these are about 1km apart
+    const KM: f64 = 0.009;
+
+    fn secs(s: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(s)
+    }
+
+    // A lap a kilometre a sample, the given number of seconds apart
+    fn lap(times: &[u64]) -> Lap {
+        let mut lap = Lap::new(LapType::Lap(1));
+        for (i, t) in times.iter().enumerate() {
+            lap.add_point(i as f64 * KM, 0.0, secs(*t));
+        }
+        lap
+    }
+
+    fn finished(times: &[u64]) -> Lap {
+        let mut lap = lap(times);
+        lap.end_time = Some(secs(*times.last().unwrap()));
+        lap.end_distance = Some(lap.distance());
+        lap
+    }
+
+    #[test]
+    fn test_elapsed_at() {
+        let reference = Reference::from_lap(&finished(&[0, 10, 30, 40])).unwrap();
+        assert_eq!(reference.lap_time(), Duration::from_secs(40));
+        assert_eq!(reference.elapsed_at(0.0), Some(Duration::ZERO));
+        let halfway = reference.elapsed_at(1500.0).unwrap();
+        assert!((halfway.as_secs_f64() - 20.0).abs() < 0.5);
+        assert!(reference.elapsed_at(10_000.0).is_none());
+    }
+
+    #[test]
+    fn test_delta() {
+        let reference = Reference::from_lap(&finished(&[0, 10, 30, 40])).unwrap();
+        // Two seconds down at the 2km mark
+        let lap = lap(&[0, 12, 32]);
+        let delta = reference.delta(&lap).unwrap();
+        assert!((delta - 2.0).abs() < 0.1);
+        let predicted = reference.predicted_lap_time(&lap).unwrap();
+        assert!((predicted.as_secs_f64() - 42.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_unfinished_lap() {
+        assert!(Reference::from_lap(&lap(&[0, 10, 20])).is_none());
+    }
+}
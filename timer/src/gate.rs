@@ -0,0 +1,153 @@
+use geo::geometry::Line;
+use geo::{coord, Coord};
+use serde::{Deserialize, Serialize};
+
+use crate::Projection;
+
+// A timing line the way track maps and surveys usually give it, a centre
+// point, the direction cars go through it and how wide it is.
+//
+// GPS noise can put the samples either side of the line just past its end,
+// so the line can be extended a little past the edges of the track. If the
+// line is still missed, passing close enough to the centre counts instead.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Gate {
+    pub centre: (f64, f64), // (lat, long)
+    pub bearing: f64,       // Direction of travel through the gate, degrees clockwise from north
+    pub width: f64,         // Metres
+    pub extension: f64,     // Metres added to each end of the line
+    pub approach: f64,      // Metres from the centre that counts as passing the gate, 0 to turn off
+}
+
+impl Gate {
+    pub fn new(centre: (f64, f64), bearing: f64, width: f64) -> Gate {
+        Gate {
+            centre,
+            bearing,
+            width,
+            extension: 0.0,
+            approach: width,
+        }
+    }
+
+    // Metres east and north of the centre
+    fn local(&self, c: Coord) -> Coord {
+        Projection::new(self.centre).project(c)
+    }
+
+    fn global(&self, c: Coord) -> Coord {
+        Projection::new(self.centre).unproject(c)
+    }
+
+    // Unit vector in the direction of travel, in local coordinates
+    fn travel(&self) -> Coord {
+        let bearing = self.bearing.to_radians();
+        coord! {x: bearing.sin(), y: bearing.cos()}
+    }
+
+    // Metres from the centre to each end of the line
+    fn half_width(&self) -> f64 {
+        self.width / 2.0 + self.extension
+    }
+
+    // The line across the track, drawn so going through it the right way
+    // is Direction::LeftToRight
+    pub fn line(&self) -> Line {
+        let half = self.half_width();
+        let t = self.travel();
+        let across = coord! {x: t.y * half, y: -t.x * half};
+        Line::new(
+            self.global(coord! {x: -across.x, y: -across.y}),
+            self.global(across),
+        )
+    }
+
+    // How far along the segment from a to b we went past the centre, if we
+    // came close enough going the right way. The closest point has to be past
+    // a and no further than b, so the same pass isn't counted by two segments.
+    //
+    // Only for passes that miss the line altogether. One heading for the line
+    // between its ends crosses it, maybe on a later segment, and that's when
+    // it counts.
+    pub(crate) fn approached(&self, a: Coord, b: Coord) -> Option<f64> {
+        if self.approach <= 0.0 {
+            return None;
+        }
+        let a = self.local(a);
+        let b = self.local(b);
+        let r = b - a;
+        let length = r.x * r.x + r.y * r.y;
+        if length == 0.0 {
+            return None;
+        }
+        let t = self.travel();
+        let towards = r.x * t.x + r.y * t.y;
+        if towards <= 0.0 {
+            return None; // Going the wrong way
+        }
+        // Where the path meets the line, carried on past its ends if need be
+        let meets = -(a.x * t.x + a.y * t.y) / towards;
+        let across = (a.x + r.x * meets) * t.y - (a.y + r.y * meets) * t.x;
+        if across.abs() <= self.half_width() {
+            return None;
+        }
+        let fraction = -(a.x * r.x + a.y * r.y) / length;
+        if fraction <= 0.0 || fraction > 1.0 {
+            return None;
+        }
+        let closest = coord! {x: a.x + r.x * fraction, y: a.y + r.y * fraction};
+        if (closest.x * closest.x + closest.y * closest.y).sqrt() > self.approach {
+            return None;
+        }
+        Some(fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Heading due east through a gate 20m wide
+    fn gate() -> Gate {
+        Gate::new((38.0, -122.0), 90.0, 20.0)
+    }
+
+    // Metres north and east of the gate
+    fn at(north: f64, east: f64) -> Coord {
+        gate().global(coord! {x: east, y: north})
+    }
+
+    #[test]
+    fn test_line() {
+        let line = gate().line();
+        let start = gate().local(line.start);
+        let end = gate().local(line.end);
+        // Runs north to south across the track, 20m long
+        assert!(start.x.abs() < 1e-6 && (start.y - 10.0).abs() < 1e-6);
+        assert!(end.x.abs() < 1e-6 && (end.y + 10.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_extension() {
+        let mut gate = gate();
+        gate.extension = 5.0;
+        let line = gate.line();
+        assert!((gate.local(line.start).y - 15.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_approached() {
+        let gate = gate();
+        // Straight through, 12m off the centre line and past the end of it
+        let fraction = gate.approached(at(12.0, -3.0), at(12.0, 1.0));
+        assert!((fraction.unwrap() - 0.75).abs() < 1e-6);
+        // Too far away
+        assert!(gate.approached(at(25.0, -3.0), at(25.0, 1.0)).is_none());
+        // The wrong way
+        assert!(gate.approached(at(12.0, 1.0), at(12.0, -3.0)).is_none());
+        // Still on the way in
+        assert!(gate.approached(at(12.0, -5.0), at(12.0, -1.0)).is_none());
+        // Close to the centre but heading through the line further on
+        assert!(gate.approached(at(1.0, -3.0), at(4.0, -0.5)).is_none());
+    }
+}
@@ -1,10 +1,28 @@
 #![allow(dead_code)]
 
 use geo::geometry::Line;
-use geo::Intersects;
-use geo::{coord, Coord};
+use geo::{coord, Coord, GeodesicDistance};
+use serde::{Deserialize, Serialize};
 use std::time;
 
+mod delta;
+mod gate;
+mod metrics;
+mod performance;
+mod pit;
+mod projection;
+mod stage;
+mod stats;
+
+pub use delta::Reference;
+pub use gate::Gate;
+pub use metrics::{LapMetrics, Motion};
+pub use performance::{Measure, PerformanceConfig, PerformanceResult, PerformanceTimer, Target};
+pub use pit::{PitEvent, PitLane, PitStop};
+pub use projection::Projection;
+pub use stage::{RunEvent, Stage, StageTimer};
+pub use stats::LapSummary;
+
 // The core model and implementation of a lap timer
 //
 // The ugly business of all of this is crossing the start/finish line..
@@ -15,53 +33,78 @@ use std::time;
 // include these two datapoints as well. We can use the ratio of line
 // segment's two halves to get a more accurate lap time.
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum LapType {
     Out,      // outlap, has not yet crossed start/finish line
     In,       // inlap, did not cross start/finish line
     Lap(u16), // lap number
 }
 
-#[derive(Clone)]
+// Times come from the samples themselves, usually the GPS time, so replaying
+// a log gives the same lap times as timing it live
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Point {
     coord: Coord,
-    time: time::Instant,
+    time: time::SystemTime,
+    distance: f64,          // Metres from the start of the lap
+    motion: Option<Motion>, // Speed and g, if we were given them
 }
 
 impl Point {
-    fn new(lat: f64, long: f64) -> Self {
+    fn new(lat: f64, long: f64, at: time::SystemTime) -> Self {
         Point {
             coord: coord! {x:lat, y:long},
-            time: time::Instant::now(),
+            time: at,
+            distance: 0.0,
+            motion: None,
         }
     }
 
+    // Metres to another point on the WGS84 ellipsoid
+    fn distance_to(&self, other: &Point) -> f64 {
+        let a = geo::Point::new(self.coord.y, self.coord.x);
+        let b = geo::Point::new(other.coord.y, other.coord.x);
+        a.geodesic_distance(&b)
+    }
+
     pub fn coord(&self) -> (f64, f64) {
         (self.coord.x, self.coord.y)
     }
 
-    pub fn at(&self) -> time::Instant {
+    pub fn at(&self) -> time::SystemTime {
         self.time
     }
+
+    pub fn motion(&self) -> Option<Motion> {
+        self.motion
+    }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Lap {
     lap_type: LapType,
-    points: Vec<Point>, // Sequence of coordinates for the lap
-    start_time: time::Instant,
-    end_time: time::Instant,
+    points: Vec<Point>,                     // Sequence of coordinates for the lap
+    start_time: Option<time::SystemTime>,   // None until the first point
+    end_time: Option<time::SystemTime>,     // None until the lap is complete
+    end_distance: Option<f64>,              // Metres to the finish, once complete
+    sectors: Vec<time::Duration>,           // Completed sector times
+    sector_start: Option<time::SystemTime>, // When the current sector started
+    valid: bool,                            // Counts towards the session's stats
+    start_coord: Option<Coord>,             // Where it crossed into this lap
 }
 
 impl Lap {
     pub fn new(lap_type: LapType) -> Lap {
-        // XXX should this really be the timestamp from the GPS?
-        let instant = time::Instant::now();
-
         Lap {
             lap_type,
             points: Vec::new(),
-            start_time: instant,
-            end_time: instant, // XXX probably an Option type
+            start_time: None,
+            end_time: None,
+            end_distance: None,
+            sectors: Vec::new(),
+            sector_start: None,
+            valid: true,
+            start_coord: None,
         }
     }
 
@@ -71,13 +114,24 @@ impl Lap {
             points: self.points.to_vec(),
             start_time: self.start_time,
             end_time: self.end_time,
+            end_distance: self.end_distance,
+            sectors: self.sectors.clone(),
+            sector_start: self.sector_start,
+            valid: self.valid,
+            start_coord: self.start_coord,
         }
     }
 
-    // Add a telemetry point to the lap
-    pub fn add_point(&mut self, lat: f64, long: f64) -> &Point {
-        // XXX we're abusing time here, this should be supplied
-        let point = Point::new(lat, long);
+    // Add a telemetry point to the lap, at is when the sample was taken
+    pub fn add_point(&mut self, lat: f64, long: f64, at: time::SystemTime) -> &Point {
+        let mut point = Point::new(lat, long, at);
+        if let Some(last) = self.points.last() {
+            point.distance = last.distance + last.distance_to(&point);
+        }
+        if self.start_time.is_none() {
+            self.start_time = Some(at);
+            self.sector_start = Some(at);
+        }
         self.points.push(point);
         self.points.last().unwrap()
     }
@@ -85,71 +139,241 @@ impl Lap {
     // Tests if the last data point intersects the line
     // XXX this feels odd here
     fn intersects(&self, line: Line) -> bool {
+        self.crossing(line, Direction::Any).is_some()
+    }
+
+    // How far along the last segment it crossed the line going the given
+    // way, from 0 at the second to last point to 1 at the last point.
+    //
+    // A point exactly on the line counts as being on its left, so a segment
+    // ending on the line crosses it and the segment leaving from there
+    // doesn't, and a segment running along the line never crosses it.
+    //
+    // The sums are done in metres on a flat projection anchored on the line.
+    // That has east as x and north as y, the mirror image of latitude and
+    // longitude, so sides are flipped back to keep Direction meaning the
+    // same.
+    fn crossing(&self, line: Line, direction: Direction) -> Option<f64> {
+        if self.points.len() < 2 {
+            return None; // We don't have at least 2 points to work with
+        }
+        let projection = Projection::new((line.start.x, line.start.y));
+        let start = projection.project(self.points[self.points.len() - 2].coord);
+        let end = projection.project(self.points[self.points.len() - 1].coord);
+        let line = Line::new(projection.project(line.start), projection.project(line.end));
+        let on_left = |c: Coord| cross(line.end - line.start, c - line.start) <= 0.0;
+        let crossed = match (on_left(start), on_left(end)) {
+            (false, true) => direction != Direction::LeftToRight,
+            (true, false) => direction != Direction::RightToLeft,
+            _ => false,
+        };
+        if !crossed {
+            return None;
+        }
+
+        // Where the two meet, along the segment and along the line
+        let r = end - start;
+        let s = line.end - line.start;
+        let q = line.start - start;
+        let denom = cross(r, s);
+        let along_line = cross(q, r) / denom;
+        if !(0.0..=1.0).contains(&along_line) {
+            return None; // Went past the end of the line
+        }
+        Some((cross(q, s) / denom).clamp(0.0, 1.0))
+    }
+
+    // Crossed the line going the given way, or failing that went past the
+    // gate the line was made from
+    fn passed(&self, line: Line, direction: Direction, gate: Option<&Gate>) -> Option<f64> {
+        if let Some(fraction) = self.crossing(line, direction) {
+            return Some(fraction);
+        }
+        let gate = gate?;
         if self.points.len() < 2 {
-            return false; // We don't have at least 2 points to work with
+            return None;
         }
         let start = &self.points[self.points.len() - 2];
         let end = &self.points[self.points.len() - 1];
-        let prev_line = geo::Line::new(start.coord, end.coord);
-        prev_line.intersects(&line)
+        gate.approached(start.coord, end.coord)
+    }
+
+    // Time at a fraction of the way along the last segment
+    fn time_at(&self, fraction: f64) -> time::SystemTime {
+        let start = &self.points[self.points.len() - 2];
+        let end = &self.points[self.points.len() - 1];
+        let elapsed = end.time.duration_since(start.time).unwrap_or_default();
+        start.time + elapsed.mul_f64(fraction)
+    }
+
+    // When the last two points crossed the line going the given way,
+    // interpolated between the two points by how far along the segment
+    // between them the line is
+    fn crossing_time(&self, line: Line, direction: Direction) -> Option<time::SystemTime> {
+        let fraction = self.crossing(line, direction)?;
+        Some(self.time_at(fraction))
     }
 
     // Creates the next lap starting with the last two points
-    // of the current lap (these intersect the start/finish line)
+    // of the current lap (these intersect the start/finish line).
+    // The lap ends at the last point, not where the line was crossed,
+    // Session::add_lap works out the crossing and ends it there.
     pub fn next_lap(&mut self) -> Lap {
+        let at = match self.points.last() {
+            Some(point) => point.time,
+            None => panic!("array shorter than 2"),
+        };
+        self.next_lap_at(at)
+    }
+
+    fn next_lap_at(&mut self, at: time::SystemTime) -> Lap {
         let lap_type = match self.lap_type {
             LapType::Out => LapType::Lap(1),
             LapType::Lap(num) => LapType::Lap(num + 1),
-            LapType::In => LapType::In,
+            LapType::In => LapType::Out, // Back out of the pits
         };
         if self.points.len() < 2 {
             panic!("array shorter than 2");
         }
 
-        // TODO The start/end time here is not quite right since the last two locations
-        // intersect the start/finish line, we will need to look at the ratio and
-        // adjust the times accordingly
-
         // XXX this is wrong but convenient to start the next lap
-        // It's possible the final point itself intersects with the line!
-        // This would lead to double counting.
-        let points = vec![self.points.pop().unwrap()];
+        // The final point can lie on the line, crossing() makes sure that
+        // doesn't count twice
+        let mut point = self.points.pop().unwrap();
+        let previous = &self.points[self.points.len() - 1];
 
-        // XXX should this really be the timestamp from the GPS?
-        let instant = time::Instant::now();
+        // How far we'd gone when we crossed, the next lap carries on from there
+        let segment = point.time.duration_since(previous.time).unwrap_or_default();
+        let into = at.duration_since(previous.time).unwrap_or_default();
+        let fraction = if segment.is_zero() {
+            1.0
+        } else {
+            into.as_secs_f64() / segment.as_secs_f64()
+        };
+        let end_distance = previous.distance + (point.distance - previous.distance) * fraction;
+        let start_coord = previous.coord + (point.coord - previous.coord) * fraction;
+        point.distance -= end_distance;
+        let points = vec![point];
 
-        self.end_time = instant;
+        self.end_time = Some(at);
+        self.end_distance = Some(end_distance);
 
         Lap {
             lap_type,
             points,
-            start_time: instant,
-            end_time: instant,
+            start_time: Some(at),
+            end_time: None,
+            end_distance: None,
+            sectors: Vec::new(),
+            sector_start: Some(at),
+            valid: true,
+            start_coord: Some(start_coord),
         }
     }
 
-    // Current time in the lap
+    // Current time in the lap, as of the latest point
     pub fn time(&self) -> time::Duration {
-        let now = time::Instant::now();
-        now.duration_since(self.start_time)
+        match (self.start_time, self.points.last()) {
+            (Some(start), Some(point)) => point.time.duration_since(start).unwrap_or_default(),
+            _ => time::Duration::ZERO,
+        }
+    }
+
+    // Metres travelled so far this lap, as of the latest point, or the whole
+    // lap once it's complete
+    pub fn distance(&self) -> f64 {
+        match self.end_distance {
+            Some(distance) => distance,
+            None => self.points.last().map_or(0.0, |point| point.distance),
+        }
+    }
+
+    // Sector times so far this lap
+    pub fn sectors(&self) -> &[time::Duration] {
+        &self.sectors
+    }
+
+    // The sector we're in, counting from 0
+    pub fn current_sector(&self) -> usize {
+        self.sectors.len()
+    }
+
+    // Current time in the sector, as of the latest point
+    pub fn sector_time(&self) -> time::Duration {
+        match (self.sector_start, self.points.last()) {
+            (Some(start), Some(point)) => point.time.duration_since(start).unwrap_or_default(),
+            _ => time::Duration::ZERO,
+        }
+    }
+
+    // Finish the current sector at the given time
+    fn split_at(&mut self, at: time::SystemTime) -> time::Duration {
+        let sector = match self.sector_start {
+            Some(start) => at.duration_since(start).unwrap_or_default(),
+            None => time::Duration::ZERO,
+        };
+        self.sectors.push(sector);
+        self.sector_start = Some(at);
+        sector
+    }
+
+    pub fn start_time(&self) -> Option<time::SystemTime> {
+        self.start_time
+    }
+
+    pub fn end_time(&self) -> Option<time::SystemTime> {
+        self.end_time
+    }
+
+    // (lat, long) where the lap started, between the two points either side
+    // of the line. None for the first lap of a session.
+    pub fn start_coord(&self) -> Option<(f64, f64)> {
+        self.start_coord.map(|c| (c.x, c.y))
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+
+    pub fn set_valid(&mut self, valid: bool) {
+        self.valid = valid;
     }
 
     pub fn number(&self) -> &LapType {
         &self.lap_type
     }
+
+    // Time taken for a completed lap
+    pub fn duration(&self) -> time::Duration {
+        match (self.start_time, self.end_time) {
+            (Some(start), Some(end)) => end.duration_since(start).unwrap_or_default(),
+            _ => time::Duration::ZERO,
+        }
+    }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Session {
-    track: Track,   // The track this session took place at
-    laps: Vec<Lap>, // List of laps
+    track: Track,                              // The track this session took place at
+    laps: Vec<Lap>,                            // List of laps
+    best_sectors: Vec<Option<time::Duration>>, // Best time for each sector on a timed lap
+    reference: Option<Reference>,              // Lap the delta is against
+    pinned: bool,                              // Keep the reference even if we go quicker
+    pit_entry: Option<time::SystemTime>,       // When we went into the pits, if we're in them
+    pit_stops: Vec<pit::PitStop>,              // Completed pit stops
 }
 
 impl Session {
     // Create a new session
     pub fn new(track: Track) -> Session {
         Session {
+            best_sectors: vec![None; track.sector_count()],
             track,
             laps: Vec::new(),
+            reference: None,
+            pinned: false,
+            pit_entry: None,
+            pit_stops: Vec::new(),
         }
     }
 
@@ -160,45 +384,221 @@ impl Session {
 
     // Adds details for a completed lap and returns the next lap
     pub fn add_lap(&mut self, lap: Lap) -> Lap {
+        let track = &self.track;
+        let at = lap
+            .passed(track.start_finish, track.direction, track.gate.as_ref())
+            .map(|fraction| lap.time_at(fraction));
         self.laps.push(lap);
         let last_lap = self.laps.len() - 1;
-        self.laps[last_lap].next_lap()
+        let mut next = match at {
+            Some(at) => self.laps[last_lap].next_lap_at(at),
+            None => self.laps[last_lap].next_lap(),
+        };
+
+        // Laps are numbered in the order they're timed, a lap that ended in
+        // the pits doesn't get a number
+        if let LapType::Lap(_) = next.lap_type {
+            let timed = self
+                .laps
+                .iter()
+                .filter(|lap| matches!(lap.lap_type, LapType::Lap(_)))
+                .count();
+            next.lap_type = LapType::Lap(timed as u16 + 1);
+        }
+
+        // The best lap so far is the reference unless one has been chosen
+        let lap = &self.laps[last_lap];
+        if !self.pinned && matches!(lap.lap_type, LapType::Lap(_)) && lap.valid {
+            let quicker = self
+                .reference
+                .as_ref()
+                .is_none_or(|r| lap.duration() < r.lap_time());
+            if quicker {
+                if let Some(reference) = Reference::from_lap(lap) {
+                    self.reference = Some(reference);
+                }
+            }
+        }
+
+        // The last sector finishes at the start/finish line, but only if we
+        // went through every split on the way
+        let lap = &mut self.laps[last_lap];
+        if !self.track.sectors.is_empty() && lap.sectors.len() == self.track.sectors.len() {
+            if let Some(end) = lap.end_time {
+                lap.split_at(end);
+            }
+        }
+
+        // Record every sector of the lap, not just the last. The others went
+        // in as we passed the splits, unless set_lap_valid has worked the
+        // best sectors out again since.
+        let lap = &self.laps[last_lap];
+        if lap.valid {
+            let (lap_type, sectors) = (lap.lap_type, lap.sectors.clone());
+            for (index, sector) in sectors.into_iter().enumerate() {
+                self.record_sector(lap_type, index, sector);
+            }
+        }
+        next
     }
 
-    // Marks the final lap as the inlap and stops the timer
-    fn finish(&mut self) {
-        let last_lap = self.laps.len() - 1;
-        self.laps[last_lap].lap_type = LapType::In;
+    // Checks if the lap just went through the next split line, returning the
+    // time for the sector it finished
+    pub fn split(&mut self, lap: &mut Lap) -> Option<time::Duration> {
+        let sector = self.track.sectors.get(lap.current_sector())?;
+        let fraction = lap.passed(sector.end, sector.direction, sector.gate.as_ref())?;
+        let at = lap.time_at(fraction);
+        let index = lap.current_sector();
+        let time = lap.split_at(at);
+        self.record_sector(lap.lap_type, index, time);
+        Some(time)
+    }
+
+    // Only timed laps count towards the best sectors, the out lap starts
+    // part way around
+    fn record_sector(&mut self, lap_type: LapType, index: usize, time: time::Duration) {
+        if !matches!(lap_type, LapType::Lap(_)) {
+            return;
+        }
+        if let Some(best) = self.best_sectors.get_mut(index) {
+            if best.is_none_or(|b| time < b) {
+                *best = Some(time);
+            }
+        }
+    }
+
+    // Best time for each sector, None until a timed lap has been through it
+    pub fn best_sectors(&self) -> &[Option<time::Duration>] {
+        &self.best_sectors
+    }
+
+    // Compare against this lap from now on, e.g. a lap from another session.
+    // None goes back to comparing against the best lap of this session.
+    pub fn set_reference(&mut self, reference: Option<Reference>) {
+        self.pinned = reference.is_some();
+        self.reference = match reference {
+            Some(reference) => Some(reference),
+            None => self.best_reference(),
+        };
+    }
+
+    pub fn reference(&self) -> Option<&Reference> {
+        self.reference.as_ref()
+    }
+
+    fn best_reference(&self) -> Option<Reference> {
+        self.best_lap().and_then(Reference::from_lap)
+    }
+
+    // Seconds behind the reference lap at the same point on track, negative
+    // when ahead. None without a reference or once past where it finished.
+    pub fn delta(&self, lap: &Lap) -> Option<f64> {
+        self.reference.as_ref()?.delta(lap)
+    }
+
+    // Lap time if the current gap to the reference holds to the line
+    pub fn predicted_lap_time(&self, lap: &Lap) -> Option<time::Duration> {
+        self.reference.as_ref()?.predicted_lap_time(lap)
+    }
+
+    // Ends the session with the lap in progress. It never made it back to
+    // start/finish so a timed lap becomes the in lap.
+    pub fn finish(&mut self, mut lap: Lap) {
+        let end = match lap.points.last() {
+            Some(point) => point.time,
+            None => return,
+        };
+        if let LapType::Lap(_) = lap.lap_type {
+            lap.lap_type = LapType::In;
+        }
+        lap.end_distance = Some(lap.distance());
+        lap.end_time = Some(end);
+        self.laps.push(lap);
     }
 
     pub fn is_lap_complete(&self, lap: &Lap) -> bool {
-        if lap.intersects(self.track.start_finish) {
-            return true;
+        let track = &self.track;
+        let at = match lap.passed(track.start_finish, track.direction, track.gate.as_ref()) {
+            Some(fraction) => lap.time_at(fraction),
+            None => return false,
+        };
+        // Too quick to be a real lap, probably a pit lane running next to
+        // the line or GPS noise. The out lap can be as short as it likes.
+        if let (LapType::Lap(_), Some(start)) = (lap.lap_type, lap.start_time) {
+            if at.duration_since(start).unwrap_or_default() < track.min_lap_time {
+                return false;
+            }
         }
-        false
+        true
+    }
+
+    // Same as Track::set_min_lap_time, for the track this session is at
+    pub fn set_min_lap_time(&mut self, min_lap_time: time::Duration) {
+        self.track.set_min_lap_time(min_lap_time);
     }
 
     pub fn current_lap_number(&self) -> usize {
         self.laps.len()
     }
+
+    // The most recently completed lap
+    pub fn last_lap(&self) -> Option<&Lap> {
+        self.laps.last()
+    }
+
+    // Every completed lap in order
+    pub fn laps(&self) -> &[Lap] {
+        &self.laps
+    }
 }
 
-struct Sector {
-    start: Line, // Beginning of the sector
-    end: Line,   // End of the sector
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sector {
+    start: Line,          // Beginning of the sector
+    end: Line,            // End of the sector, the split line
+    direction: Direction, // Which way the split line is crossed
+    gate: Option<Gate>,   // The gate the split line was made from
 }
 
 impl Sector {
     // Create a new sector on the track
     pub fn new(start: Line, end: Line) -> Sector {
-        Sector { start, end }
+        Sector {
+            start,
+            end,
+            direction: Direction::Any,
+            gate: None,
+        }
     }
 }
 
+// Which way a line has to be crossed to count. Facing along the line from
+// its start to its end, LeftToRight is crossing from the right hand side to
+// the left hand side as they are on a map, e.g. heading north over a line
+// drawn from west to east. RightToLeft is the other way. The names are from
+// the maths, which has latitude as x and longitude as y. Gates pick the
+// direction for you.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Direction {
+    Any,
+    LeftToRight,
+    RightToLeft,
+}
+
+// z component of the cross product, positive when b is to the left of a
+fn cross(a: Coord, b: Coord) -> f64 {
+    a.x * b.y - a.y * b.x
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Track {
-    name: String,         // Name of the track and configuration
-    start_finish: Line,   // Start/Finish line coordinates
-    sectors: Vec<Sector>, // List of track sectors
+    name: String,                 // Name of the track and configuration
+    start_finish: Line,           // Start/Finish line coordinates
+    direction: Direction,         // Which way start/finish is crossed
+    gate: Option<Gate>,           // The gate start/finish was made from
+    sectors: Vec<Sector>,         // List of track sectors
+    pit_lane: Option<PitLane>,    // Where the pits are, if we know
+    min_lap_time: time::Duration, // Shortest a timed lap can be
 }
 
 impl Track {
@@ -210,20 +610,97 @@ impl Track {
                 geo::coord! {x:sf_start.0, y:sf_start.1},
                 geo::coord! {x:sf_end.0, y:sf_end.1},
             ),
+            direction: Direction::Any,
+            gate: None,
             sectors: Vec::new(),
+            pit_lane: None,
+            min_lap_time: time::Duration::ZERO,
         }
     }
 
-    // Add a new sector to the track
-    fn add_sector(&mut self, sector: Sector) {
+    // Creates a new track with start/finish given as a gate
+    pub fn from_gate(name: String, gate: Gate) -> Track {
+        Track {
+            name,
+            start_finish: gate.line(),
+            direction: Direction::LeftToRight,
+            gate: Some(gate),
+            sectors: Vec::new(),
+            pit_lane: None,
+            min_lap_time: time::Duration::ZERO,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Only count laps crossing start/finish this way
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+    }
+
+    // Add a new sector to the track, sectors are timed in the order they're
+    // added. The final sector runs from the last split to start/finish.
+    pub fn add_sector(&mut self, sector: Sector) {
         self.sectors.push(sector);
     }
+
+    // Add a split line, the sector before it starts at the previous split
+    pub fn add_split(&mut self, split_start: (f64, f64), split_end: (f64, f64)) {
+        let start = match self.sectors.last() {
+            Some(sector) => sector.end,
+            None => self.start_finish,
+        };
+        let end = geo::Line::new(
+            geo::coord! {x:split_start.0, y:split_start.1},
+            geo::coord! {x:split_end.0, y:split_end.1},
+        );
+        self.add_sector(Sector::new(start, end));
+    }
+
+    pub fn set_pit_lane(&mut self, pit_lane: PitLane) {
+        self.pit_lane = Some(pit_lane);
+    }
+
+    // Crossings sooner than this after the start of a lap are ignored, so
+    // live timing and replaying the log agree on what counts as a lap
+    pub fn set_min_lap_time(&mut self, min_lap_time: time::Duration) {
+        self.min_lap_time = min_lap_time;
+    }
+
+    // Add a split given as a gate
+    pub fn add_split_gate(&mut self, gate: Gate) {
+        let start = match self.sectors.last() {
+            Some(sector) => sector.end,
+            None => self.start_finish,
+        };
+        self.add_sector(Sector {
+            start,
+            end: gate.line(),
+            direction: Direction::LeftToRight,
+            gate: Some(gate),
+        });
+    }
+
+    // Number of sector times in a lap, 0 if the track has no splits
+    pub fn sector_count(&self) -> usize {
+        match self.sectors.len() {
+            0 => 0,
+            n => n + 1,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // Samples 40ms apart, like the RaceBox Mini at 25Hz
+    fn at(sample: u64) -> time::SystemTime {
+        time::UNIX_EPOCH + time::Duration::from_millis(1_000_000 + sample * 40)
+    }
+
     #[test]
     fn test_lap() {
         let lap = Lap::new(LapType::Out);
@@ -242,23 +719,343 @@ mod tests {
         let mut lap = session.start();
         assert_eq!(lap.lap_type, LapType::Out);
 
-        lap.add_point(1.0, 1.0);
+        lap.add_point(1.0, 1.0, at(0));
         assert!(!lap.intersects(sf_line)); // No intersection
 
-        lap.add_point(2.0, 2.0);
+        lap.add_point(2.0, 2.0, at(1));
         assert!(!lap.intersects(sf_line)); // No intersection
 
-        lap.add_point(3.0, 3.0);
+        lap.add_point(3.0, 3.0, at(2));
         assert!(lap.intersects(sf_line)); // Intersection
 
         // Move to the next lap since we crossed start/finish
         lap = lap.next_lap();
         assert!(matches!(lap.lap_type, LapType::Lap(1)));
 
-        lap.add_point(4.0, 4.0);
+        lap.add_point(4.0, 4.0, at(3));
         assert!(!lap.intersects(sf_line)); // No intersection
     }
 
+    #[test]
+    fn test_last_lap() {
+        let track = Track::new("Sonoma".to_string(), (2.1, 1.0), (2.6, 4.0));
+        let mut session = Session::new(track);
+        assert!(session.last_lap().is_none());
+
+        let mut lap = session.start();
+        lap.add_point(2.0, 2.0, at(0));
+        lap.add_point(3.0, 3.0, at(1));
+        assert!(session.is_lap_complete(&lap));
+        lap = session.add_lap(lap);
+
+        let last_lap = session.last_lap().unwrap();
+        assert_eq!(*last_lap.number(), LapType::Out);
+        assert!(last_lap.duration() < time::Duration::from_millis(40));
+        assert_eq!(*lap.number(), LapType::Lap(1));
+    }
+
+    #[test]
+    fn test_crossing_time() {
+        let track = Track::new("Sonoma".to_string(), (2.5, 0.0), (2.5, 10.0));
+        let mut session = Session::new(track);
+        let mut lap = session.start();
+
+        // A quarter of the way between two samples 40ms apart
+        lap.add_point(2.0, 1.0, at(0));
+        lap.add_point(4.0, 1.0, at(1));
+        assert!(session.is_lap_complete(&lap));
+        let next = session.add_lap(lap);
+
+        let crossed = at(0) + time::Duration::from_millis(10);
+        assert_eq!(session.last_lap().unwrap().end_time(), Some(crossed));
+        assert_eq!(next.start_time(), Some(crossed));
+        let (lat, long) = next.start_coord().unwrap();
+        assert!((lat - 2.5).abs() < 1e-6);
+        assert!((long - 1.0).abs() < 1e-9);
+        assert_eq!(session.last_lap().unwrap().start_coord(), None);
+    }
+
+    #[test]
+    fn test_lap_times_from_samples() {
+        let track = Track::new("Sonoma".to_string(), (2.5, 0.0), (2.5, 10.0));
+        let mut session = Session::new(track);
+        let mut lap = session.start();
+
+        // Across the line on the way up and again on the way back down
+        for (i, x) in [1.0, 2.0, 3.0, 4.0, 3.0, 2.0, 1.0].iter().enumerate() {
+            lap.add_point(*x, 1.0, at(i as u64));
+            if session.is_lap_complete(&lap) {
+                lap = session.add_lap(lap);
+            }
+        }
+        // Crossed half way between samples 1 and 2, and 4 and 5
+        let last_lap = session.last_lap().unwrap();
+        assert_eq!(*last_lap.number(), LapType::Lap(1));
+        assert_eq!(last_lap.duration(), time::Duration::from_millis(120));
+        assert_eq!(lap.time(), time::Duration::from_millis(60));
+    }
+
+    #[test]
+    fn test_sectors() {
+        // Up x past a split at 5 and back down through start/finish at 2.5,
+        // one sample every 40ms
+        let mut track = Track::new("Sonoma".to_string(), (2.5, 0.0), (2.5, 10.0));
+        track.add_split((5.0, 0.0), (5.0, 10.0));
+        assert_eq!(track.sector_count(), 2);
+        let mut session = Session::new(track);
+        let mut lap = session.start();
+
+        let xs = [
+            1.0, 2.0, 3.0, 4.0, 6.0, 7.0, 6.0, 4.0, 3.0, 2.0, 1.0, 2.0, 3.0, 4.0, 6.0,
+        ];
+        let mut splits = Vec::new();
+        for (i, x) in xs.iter().enumerate() {
+            lap.add_point(*x, 1.0, at(i as u64));
+            if let Some(split) = session.split(&mut lap) {
+                splits.push(split);
+            }
+            if session.is_lap_complete(&lap) {
+                lap = session.add_lap(lap);
+            }
+        }
+
+        // Start/finish at 2.5 on the way up is the start of lap 1
+        let lap1 = &session.laps[1];
+        assert_eq!(*lap1.number(), LapType::Lap(1));
+        assert_eq!(lap1.sectors().len(), 2);
+        assert_eq!(
+            lap1.sectors().iter().sum::<time::Duration>(),
+            lap1.duration()
+        );
+        assert_eq!(
+            session.best_sectors()[0],
+            Some(time::Duration::from_millis(80))
+        );
+        assert_eq!(
+            session.best_sectors()[1],
+            Some(time::Duration::from_millis(200))
+        );
+
+        // Coming back down through start/finish counts as a lap for now,
+        // that makes this sector 2 of lap 3
+        assert_eq!(*lap.number(), LapType::Lap(3));
+        assert_eq!(lap.current_sector(), 1);
+        assert_eq!(splits.len(), 2);
+        assert!(lap.sector_time() < time::Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_direction() {
+        // Looking up y along the line, travelling up x crosses it from left
+        // to right
+        let mut track = Track::new("Sonoma".to_string(), (2.5, 0.0), (2.5, 10.0));
+        track.set_direction(Direction::LeftToRight);
+        let session = Session::new(track.clone());
+        let mut lap = session.start();
+        lap.add_point(3.0, 1.0, at(0));
+        lap.add_point(2.0, 1.0, at(1));
+        assert!(!session.is_lap_complete(&lap));
+        lap.add_point(3.0, 1.0, at(2));
+        assert!(session.is_lap_complete(&lap));
+
+        track.set_direction(Direction::RightToLeft);
+        let session = Session::new(track);
+        assert!(!session.is_lap_complete(&lap));
+    }
+
+    #[test]
+    fn test_point_on_line() {
+        let track = Track::new("Sonoma".to_string(), (2.5, 0.0), (2.5, 10.0));
+        let mut session = Session::new(track);
+        let mut lap = session.start();
+        let mut laps = 0;
+        // Lands exactly on the line, runs along it and carries on. The line
+        // counts as being on its left so it's crossed leaving the line.
+        for (i, (x, y)) in [(2.0, 1.0), (2.5, 1.0), (2.5, 2.0), (3.0, 2.0)]
+            .iter()
+            .enumerate()
+        {
+            lap.add_point(*x, *y, at(i as u64));
+            if session.is_lap_complete(&lap) {
+                lap = session.add_lap(lap);
+                laps += 1;
+            }
+        }
+        assert_eq!(laps, 1);
+        assert_eq!(session.last_lap().unwrap().end_time(), Some(at(2)));
+    }
+
+    #[test]
+    fn test_past_end_of_line() {
+        let track = Track::new("Sonoma".to_string(), (2.5, 0.0), (2.5, 10.0));
+        let session = Session::new(track);
+        let mut lap = session.start();
+        lap.add_point(2.0, 11.0, at(0));
+        lap.add_point(3.0, 11.0, at(1));
+        assert!(!session.is_lap_complete(&lap));
+    }
+
+    #[test]
+    fn test_min_lap_time() {
+        let track = Track::new("Sonoma".to_string(), (2.5, 0.0), (2.5, 10.0));
+        let mut session = Session::new(track);
+        session.set_min_lap_time(time::Duration::from_millis(200));
+        let mut lap = session.start();
+        let mut laps = 0;
+        // Back and forth across the line every 120ms
+        for (i, x) in [2.0, 3.0, 2.0, 3.0, 4.0, 5.0, 4.0, 3.0, 2.0]
+            .iter()
+            .enumerate()
+        {
+            lap.add_point(*x, 1.0, at(i as u64));
+            if session.is_lap_complete(&lap) {
+                lap = session.add_lap(lap);
+                laps += 1;
+            }
+        }
+        // The out lap ends straight away, the next two crossings are too soon
+        assert_eq!(laps, 2);
+        assert_eq!(
+            session.last_lap().unwrap().duration(),
+            time::Duration::from_millis(280)
+        );
+    }
+
+    #[test]
+    fn test_gate() {
+        // Heading north through a 10m gate, then round and north again
+        // through the gate just past its west end
+        let gate = Gate::new((38.0, -122.0), 0.0, 10.0);
+        let track = Track::from_gate("Sonoma".to_string(), gate);
+        let mut session = Session::new(track);
+        let mut lap = session.start();
+        let mut laps = 0;
+        let m = 1.0 / 111_320.0; // About a metre of latitude
+        let points = [
+            (38.0 - 5.0 * m, -122.0),
+            (38.0 + 5.0 * m, -122.0),
+            (38.0 + 5.0 * m, -122.0 + 60.0 * m),
+            (38.0 - 5.0 * m, -122.0 + 60.0 * m),
+            (38.0 - 5.0 * m, -122.0 - 8.0 * m),
+            (38.0 + 5.0 * m, -122.0 - 8.0 * m),
+        ];
+        for (i, (lat, long)) in points.iter().enumerate() {
+            lap.add_point(*lat, *long, at(i as u64));
+            if session.is_lap_complete(&lap) {
+                lap = session.add_lap(lap);
+                laps += 1;
+            }
+        }
+        // Going south doesn't count, missing the end of the line does
+        assert_eq!(laps, 2);
+        let last_lap = session.last_lap().unwrap();
+        assert_eq!(*last_lap.number(), LapType::Lap(1));
+        assert_eq!(
+            last_lap.end_time(),
+            Some(at(4) + time::Duration::from_millis(20))
+        );
+    }
+
+    #[test]
+    fn test_gate_diagonal() {
+        // Heading north east through a 10m gate. The segment before the
+        // crossing passes close to the centre but only the crossing counts.
+        let gate = Gate::new((38.0, -122.0), 0.0, 10.0);
+        let mut track = Track::from_gate("Sonoma".to_string(), gate);
+        track.set_min_lap_time(time::Duration::from_millis(20));
+        let mut session = Session::new(track);
+        let mut lap = session.start();
+        let projection = Projection::new((38.0, -122.0));
+        let mut completed = Vec::new();
+        for (i, (east, north)) in [(-5.0, -9.0), (1.0, -3.0), (3.5, -0.5), (6.0, 2.0)]
+            .iter()
+            .enumerate()
+        {
+            let (lat, long) = projection.to_global(geo::coord! {x: *east, y: *north});
+            lap.add_point(lat, long, at(i as u64));
+            if session.is_lap_complete(&lap) {
+                lap = session.add_lap(lap);
+                completed.push(i);
+            }
+        }
+        assert_eq!(completed, vec![3]);
+        // The next lap starts at the line, not where we passed the centre
+        assert!(lap.start_time().unwrap() > at(2));
+    }
+
+    #[test]
+    fn test_reference() {
+        // Back and forth across start/finish at latitude 2.5
+        let track = Track::new("Test".to_string(), (2.5, 0.0), (2.5, 10.0));
+        let mut session = Session::new(track);
+        session.set_min_lap_time(time::Duration::from_millis(100));
+        let mut lap = session.start();
+        let lats = [1.0, 2.0, 3.0, 4.0, 3.0, 2.0, 3.0, 4.0, 3.0, 2.0];
+        for (i, lat) in lats.iter().enumerate() {
+            lap.add_point(*lat, 1.0, at(i as u64));
+            if i == 7 {
+                // Level with the first timed lap at the same distance
+                assert!(session.delta(&lap).unwrap().abs() < 1e-6);
+                let predicted = session.predicted_lap_time(&lap).unwrap();
+                assert_eq!(predicted.as_millis(), 120);
+            }
+            if session.is_lap_complete(&lap) {
+                lap = session.add_lap(lap);
+            }
+        }
+        // The out lap is never the reference
+        let reference = session.reference().unwrap();
+        assert_eq!(reference.lap_type(), LapType::Lap(1));
+        assert!(lap.distance() > 0.0);
+
+        // Pinning one keeps it, clearing it goes back to the best lap
+        let lap_2 = Reference::from_lap(&session.laps[2]);
+        assert_eq!(lap_2.as_ref().unwrap().lap_type(), LapType::Lap(2));
+        session.set_reference(lap_2);
+        assert_eq!(session.reference().unwrap().lap_type(), LapType::Lap(2));
+        session.set_reference(None);
+        assert_eq!(session.reference().unwrap().lap_type(), LapType::Lap(1));
+    }
+
+    #[test]
+    fn test_serde() {
+        let mut track = Track::new("Test".to_string(), (2.5, 0.0), (2.5, 10.0));
+        track.add_split((3.5, 0.0), (3.5, 10.0));
+        let mut session = Session::new(track);
+        session.set_min_lap_time(time::Duration::from_millis(100));
+        let mut lap = session.start();
+        let lats = [1.0, 2.0, 3.0, 4.0, 3.0, 2.0, 3.0, 4.0];
+        for (i, lat) in lats.iter().enumerate() {
+            lap.add_point(*lat, 1.0, at(i as u64));
+            session.split(&mut lap);
+            if session.is_lap_complete(&lap) {
+                lap = session.add_lap(lap);
+            }
+        }
+
+        // Pick up where we left off, like after a restart
+        let json = serde_json::to_string(&session).unwrap();
+        let mut restored: Session = serde_json::from_str(&json).unwrap();
+        let lap_json = serde_json::to_string(&lap).unwrap();
+        let mut lap: Lap = serde_json::from_str(&lap_json).unwrap();
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json);
+        assert_eq!(restored.laps().len(), 2);
+        assert_eq!(restored.track.name(), "Test");
+        assert_eq!(restored.reference(), session.reference());
+        assert_eq!(
+            lap.start_time(),
+            Some(at(4) + time::Duration::from_millis(20))
+        );
+
+        for (i, lat) in [3.0, 2.0].iter().enumerate() {
+            lap.add_point(*lat, 1.0, at(i as u64 + 8));
+            if restored.is_lap_complete(&lap) {
+                restored.add_lap(lap.copy());
+            }
+        }
+        assert_eq!(*restored.last_lap().unwrap().number(), LapType::Lap(2));
+    }
+
     #[test]
     fn test_track() {
         let track = Track::new("Sonoma".to_string(), (1.0, 1.0), (2.0, 2.0));
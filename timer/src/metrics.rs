@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+use crate::{Lap, Point};
+
+// What the car was doing at a point, from the full telemetry sample
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Motion {
+    pub speed: f32,          // kph
+    pub lateral_g: f32,      // Positive to the right
+    pub longitudinal_g: f32, // Positive accelerating
+}
+
+impl Motion {
+    pub fn combined_g(&self) -> f32 {
+        self.lateral_g.hypot(self.longitudinal_g)
+    }
+}
+
+// How a lap went, beyond how long it took. Speeds and g are None when the
+// lap only had positions.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LapMetrics {
+    pub distance: f64,      // Metres
+    pub average_speed: f32, // kph, distance over time
+    pub max_speed: Option<f32>,
+    pub min_speed: Option<f32>,
+    pub max_lateral_g: Option<f32>,      // Either way
+    pub max_longitudinal_g: Option<f32>, // Accelerating or braking
+    pub max_combined_g: Option<f32>,
+    pub top_speed_at: Option<(f64, f64)>, // (lat, long)
+}
+
+impl Lap {
+    // Same as add_point, keeping what the car was doing as well
+    pub fn add_sample(&mut self, lat: f64, long: f64, at: SystemTime, motion: Motion) -> &Point {
+        self.add_point(lat, long, at);
+        let last = self.points.len() - 1;
+        self.points[last].motion = Some(motion);
+        &self.points[last]
+    }
+
+    // Metrics for the lap so far, or the whole lap once it's complete
+    pub fn metrics(&self) -> LapMetrics {
+        let time = match self.end_time {
+            Some(_) => self.duration(),
+            None => self.time(),
+        };
+        let distance = self.distance();
+        let average_speed = if time.is_zero() {
+            0.0
+        } else {
+            (distance / time.as_secs_f64() * 3.6) as f32
+        };
+
+        let mut metrics = LapMetrics {
+            distance,
+            average_speed,
+            ..Default::default()
+        };
+        let max = |current: Option<f32>, value: f32| Some(current.map_or(value, |c| c.max(value)));
+        for point in &self.points {
+            let motion = match point.motion {
+                Some(motion) => motion,
+                None => continue,
+            };
+            if metrics.max_speed.is_none_or(|max| motion.speed > max) {
+                metrics.max_speed = Some(motion.speed);
+                metrics.top_speed_at = Some(point.coord());
+            }
+            metrics.min_speed = Some(
+                metrics
+                    .min_speed
+                    .map_or(motion.speed, |min| min.min(motion.speed)),
+            );
+            metrics.max_lateral_g = max(metrics.max_lateral_g, motion.lateral_g.abs());
+            metrics.max_longitudinal_g =
+                max(metrics.max_longitudinal_g, motion.longitudinal_g.abs());
+            metrics.max_combined_g = max(metrics.max_combined_g, motion.combined_g());
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LapType;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn motion(speed: f32, lateral_g: f32, longitudinal_g: f32) -> Motion {
+        Motion {
+            speed,
+            lateral_g,
+            longitudinal_g,
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut lap = Lap::new(LapType::Lap(1));
+        // About 1km north over 30 seconds
+        let samples = [
+            (0.000, motion(80.0, 0.1, 0.3)),
+            (0.003, motion(160.0, -1.2, 0.0)),
+            (0.006, motion(120.0, 0.6, -0.8)),
+            (0.009, motion(100.0, 0.0, 0.2)),
+        ];
+        for (i, (lat, motion)) in samples.iter().enumerate() {
+            let at = UNIX_EPOCH + Duration::from_millis(i as u64 * 10_000);
+            lap.add_sample(*lat, 0.0, at, *motion);
+        }
+
+        let metrics = lap.metrics();
+        assert!((metrics.distance - 995.2).abs() < 1.0);
+        assert!((metrics.average_speed - 119.4).abs() < 0.5);
+        assert_eq!(metrics.max_speed, Some(160.0));
+        assert_eq!(metrics.min_speed, Some(80.0));
+        assert_eq!(metrics.top_speed_at, Some((0.003, 0.0)));
+        assert_eq!(metrics.max_lateral_g, Some(1.2));
+        assert_eq!(metrics.max_longitudinal_g, Some(0.8));
+        assert_eq!(metrics.max_combined_g, Some(1.2));
+    }
+
+    #[test]
+    fn test_metrics_without_motion() {
+        let mut lap = Lap::new(LapType::Lap(1));
+        lap.add_point(0.0, 0.0, UNIX_EPOCH);
+        lap.add_point(0.001, 0.0, UNIX_EPOCH + Duration::from_secs(4));
+        let metrics = lap.metrics();
+        assert!(metrics.distance > 100.0);
+        assert!(metrics.average_speed > 90.0);
+        assert!(metrics.max_speed.is_none());
+        assert!(metrics.top_speed_at.is_none());
+    }
+}
@@ -0,0 +1,403 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+// Acceleration, drag strip and braking times, worked out from speed alone so
+// it doesn't need a track.
+
+const STOPPED_KPH: f32 = 0.5; // Slower than this is standing still
+const MPH: f32 = 1.609_344; // kph
+const FOOT: f64 = 0.3048; // Metres
+const MILE: f64 = 1609.344; // Metres
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Measure {
+    Speed { from: f32, to: f32 }, // kph, from 0 is timed from a standing start
+    Distance(f64),                // Metres from a standing start
+    Braking { from: f32 },        // kph, to a stop
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Target {
+    pub name: String,
+    pub measure: Measure,
+}
+
+impl Target {
+    pub fn new(name: &str, measure: Measure) -> Target {
+        Target {
+            name: name.to_string(),
+            measure,
+        }
+    }
+
+    // Timed from the launch rather than from passing a speed
+    fn is_standing(&self) -> bool {
+        match self.measure {
+            Measure::Speed { from, .. } => from <= 0.0,
+            Measure::Distance(_) => true,
+            Measure::Braking { .. } => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PerformanceConfig {
+    pub rollout: f64, // Metres moved before standing starts are timed, a foot at the drag strip
+    pub launch_g: f32, // Forward g that counts as launching
+    pub targets: Vec<Target>,
+}
+
+impl Default for PerformanceConfig {
+    fn default() -> Self {
+        PerformanceConfig {
+            rollout: FOOT,
+            launch_g: 0.15,
+            targets: vec![
+                Target::new(
+                    "0-60 mph",
+                    Measure::Speed {
+                        from: 0.0,
+                        to: 60.0 * MPH,
+                    },
+                ),
+                Target::new(
+                    "0-100 km/h",
+                    Measure::Speed {
+                        from: 0.0,
+                        to: 100.0,
+                    },
+                ),
+                Target::new(
+                    "100-200 km/h",
+                    Measure::Speed {
+                        from: 100.0,
+                        to: 200.0,
+                    },
+                ),
+                Target::new("60 ft", Measure::Distance(60.0 * FOOT)),
+                Target::new("1/8 mile", Measure::Distance(MILE / 8.0)),
+                Target::new("1/4 mile", Measure::Distance(MILE / 4.0)),
+                Target::new("100-0 km/h", Measure::Braking { from: 100.0 }),
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PerformanceResult {
+    pub name: String,
+    pub start: SystemTime,
+    pub time: Duration,
+    pub distance: f64, // Metres covered
+    pub speed: f32,    // kph at the end, the trap speed for distances
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Sample {
+    at: SystemTime,
+    speed: f32,    // kph
+    distance: f64, // Metres since we started
+}
+
+impl Sample {
+    // Part way from here to the next sample
+    fn towards(&self, next: &Sample, fraction: f64) -> Sample {
+        let elapsed = next.at.duration_since(self.at).unwrap_or_default();
+        Sample {
+            at: self.at + elapsed.mul_f64(fraction.clamp(0.0, 1.0)),
+            speed: self.speed + (next.speed - self.speed) * fraction as f32,
+            distance: self.distance + (next.distance - self.distance) * fraction,
+        }
+    }
+
+    // Where speed got to the given speed between here and the next sample
+    fn at_speed(&self, next: &Sample, speed: f32) -> Sample {
+        let fraction = (speed - self.speed) / (next.speed - self.speed);
+        self.towards(next, fraction as f64)
+    }
+
+    fn at_distance(&self, next: &Sample, distance: f64) -> Sample {
+        let fraction = (distance - self.distance) / (next.distance - self.distance);
+        self.towards(next, fraction)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Moving,           // Rolling, waiting to stop before the next launch
+    Stopped,          // Staged, waiting to launch
+    Launched(Sample), // Where we launched, until the rollout is done
+    Running,          // Standing start targets are being timed
+}
+
+// Feed it every sample, it hands back results as they're made
+pub struct PerformanceTimer {
+    config: PerformanceConfig,
+    state: State,
+    last: Option<Sample>,
+    starts: Vec<Option<Sample>>, // Where each target started being timed
+}
+
+impl PerformanceTimer {
+    pub fn new(config: PerformanceConfig) -> PerformanceTimer {
+        PerformanceTimer {
+            starts: vec![None; config.targets.len()],
+            config,
+            state: State::Moving,
+            last: None,
+        }
+    }
+
+    // Speed in kph and forward g, positive when accelerating
+    pub fn update(&mut self, speed: f32, long_g: f32, at: SystemTime) -> Vec<PerformanceResult> {
+        let last = match self.last {
+            Some(last) => last,
+            None => {
+                self.last = Some(Sample {
+                    at,
+                    speed,
+                    distance: 0.0,
+                });
+                self.state = self.stopped_or_moving(speed);
+                return Vec::new();
+            }
+        };
+        // Distance from the average speed over the gap between samples
+        let elapsed = at.duration_since(last.at).unwrap_or_default().as_secs_f64();
+        let travelled = (last.speed + speed) as f64 / 2.0 / 3.6 * elapsed;
+        let sample = Sample {
+            at,
+            speed,
+            distance: last.distance + travelled,
+        };
+        self.last = Some(sample);
+
+        self.launch(&last, &sample, long_g);
+        let mut results = Vec::new();
+        for (i, target) in self.config.targets.iter().enumerate() {
+            let start = &mut self.starts[i];
+            if let Some(end) = Self::measure(target, start, &last, &sample) {
+                if let Some(begin) = start.take() {
+                    results.push(PerformanceResult {
+                        name: target.name.clone(),
+                        start: begin.at,
+                        time: end.at.duration_since(begin.at).unwrap_or_default(),
+                        distance: end.distance - begin.distance,
+                        speed: end.speed,
+                    });
+                }
+            }
+        }
+        results
+    }
+
+    fn stopped_or_moving(&self, speed: f32) -> State {
+        if speed <= STOPPED_KPH {
+            State::Stopped
+        } else {
+            State::Moving
+        }
+    }
+
+    // Spots the launch and starts timing the standing start targets once
+    // we've rolled out
+    fn launch(&mut self, last: &Sample, sample: &Sample, long_g: f32) {
+        let standing = |timer: &mut Self, start: Option<Sample>| {
+            for (i, target) in timer.config.targets.iter().enumerate() {
+                if target.is_standing() {
+                    timer.starts[i] = start;
+                }
+            }
+        };
+        self.state = match self.state {
+            _ if sample.speed <= STOPPED_KPH && long_g < self.config.launch_g => {
+                standing(self, None);
+                State::Stopped
+            }
+            // Without g we only know we've gone once we're moving, so go
+            // from the last sample we were stopped at
+            State::Stopped if long_g >= self.config.launch_g => State::Launched(*sample),
+            State::Stopped => State::Launched(*last),
+            State::Launched(launch) => State::Launched(launch),
+            state => state,
+        };
+        if let State::Launched(launch) = self.state {
+            let rolled_out = launch.distance + self.config.rollout;
+            if sample.distance >= rolled_out {
+                let start = if last.distance < rolled_out {
+                    last.at_distance(sample, rolled_out)
+                } else {
+                    launch
+                };
+                standing(self, Some(start));
+                self.state = State::Running;
+            }
+        }
+    }
+
+    // Where the target finished between the two samples, if it did. Starts
+    // or stops timing targets that go by speed along the way.
+    fn measure(
+        target: &Target,
+        start: &mut Option<Sample>,
+        last: &Sample,
+        sample: &Sample,
+    ) -> Option<Sample> {
+        match target.measure {
+            Measure::Speed { from, to } => {
+                if from > 0.0 {
+                    if last.speed < from && sample.speed >= from {
+                        *start = Some(last.at_speed(sample, from));
+                    } else if sample.speed < from {
+                        *start = None; // Lifted before we got there
+                    }
+                }
+                (*start)?;
+                if last.speed < to && sample.speed >= to {
+                    return Some(last.at_speed(sample, to));
+                }
+                None
+            }
+            Measure::Distance(distance) => {
+                let end = (*start)?.distance + distance;
+                if sample.distance >= end {
+                    return Some(last.at_distance(sample, end));
+                }
+                None
+            }
+            Measure::Braking { from } => {
+                if last.speed > from && sample.speed <= from {
+                    *start = Some(last.at_speed(sample, from));
+                } else if sample.speed > from {
+                    *start = None; // Back on the throttle
+                }
+                (*start)?;
+                if sample.speed <= STOPPED_KPH {
+                    return Some(last.at_speed(sample, STOPPED_KPH));
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    // Samples a tenth of a second apart
+    fn run(timer: &mut PerformanceTimer, speeds: &[(f32, f32)]) -> Vec<PerformanceResult> {
+        speeds
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (speed, g))| {
+                timer.update(
+                    *speed,
+                    *g,
+                    UNIX_EPOCH + Duration::from_millis(i as u64 * 100),
+                )
+            })
+            .collect()
+    }
+
+    fn config(targets: Vec<Target>) -> PerformanceConfig {
+        PerformanceConfig {
+            rollout: 0.0,
+            targets,
+            ..Default::default()
+        }
+    }
+
+    fn secs(result: &PerformanceResult) -> f64 {
+        result.time.as_secs_f64()
+    }
+
+    #[test]
+    fn test_acceleration() {
+        let targets = vec![
+            Target::new(
+                "0-100",
+                Measure::Speed {
+                    from: 0.0,
+                    to: 100.0,
+                },
+            ),
+            Target::new(
+                "50-100",
+                Measure::Speed {
+                    from: 50.0,
+                    to: 100.0,
+                },
+            ),
+        ];
+        let mut timer = PerformanceTimer::new(config(targets));
+        // Sitting still, launch, then 10kph more every sample
+        let mut speeds = vec![(0.0, 0.0), (0.0, 0.5)];
+        speeds.extend((1..=12).map(|i| (i as f32 * 10.0, 0.5)));
+        let results = run(&mut timer, &speeds);
+
+        assert_eq!(results.len(), 2);
+        // Launched at the sample with the g, 0.1s
+        assert_eq!(results[0].name, "0-100");
+        assert!((secs(&results[0]) - 1.0).abs() < 1e-6);
+        assert_eq!(results[0].speed, 100.0);
+        assert_eq!(results[1].name, "50-100");
+        assert!((secs(&results[1]) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_distance_and_rollout() {
+        let targets = vec![Target::new("100 m", Measure::Distance(100.0))];
+        // 36kph is 10 m/s, a metre every sample
+        let speeds: Vec<(f32, f32)> = [(0.0, 0.0)]
+            .into_iter()
+            .chain(std::iter::repeat_n((36.0, 0.0), 200))
+            .collect();
+
+        let mut timer = PerformanceTimer::new(config(targets.clone()));
+        let results = run(&mut timer, &speeds);
+        // Half a metre in the first sample, speeding up from a stop
+        assert_eq!(results.len(), 1);
+        assert!((secs(&results[0]) - 10.05).abs() < 1e-6);
+        assert!((results[0].distance - 100.0).abs() < 1e-6);
+        assert_eq!(results[0].speed, 36.0);
+
+        // Timing starts once we've moved a foot
+        let mut timer = PerformanceTimer::new(PerformanceConfig {
+            rollout: FOOT,
+            ..config(targets)
+        });
+        let results = run(&mut timer, &speeds);
+        assert!((secs(&results[0]) - (10.05 - FOOT * 0.1)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_braking() {
+        let targets = vec![Target::new("100-0", Measure::Braking { from: 100.0 })];
+        let mut timer = PerformanceTimer::new(config(targets));
+        let speeds: Vec<(f32, f32)> = [120.0, 110.0, 90.0, 60.0, 30.0, 0.0]
+            .iter()
+            .map(|speed| (*speed, -1.0))
+            .collect();
+        let results = run(&mut timer, &speeds);
+        assert_eq!(results.len(), 1);
+        // From half way between 110 and 90 to just before stopping
+        let expected = 0.35 - 0.5 / 30.0 * 0.1;
+        assert!((secs(&results[0]) - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_no_launch_while_rolling() {
+        let targets = vec![Target::new(
+            "0-100",
+            Measure::Speed {
+                from: 0.0,
+                to: 100.0,
+            },
+        )];
+        let mut timer = PerformanceTimer::new(config(targets));
+        let speeds: Vec<(f32, f32)> = (5..15).map(|i| (i as f32 * 10.0, 0.5)).collect();
+        assert!(run(&mut timer, &speeds).is_empty());
+    }
+}
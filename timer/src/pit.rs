@@ -0,0 +1,252 @@
+use geo::geometry::{Line, LineString, Polygon};
+use geo::{coord, Contains};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+use crate::{Direction, Lap, LapType, Session};
+
+// Where the pit lane is, either lines across the pit entry and exit or the
+// outline of the whole pit lane
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PitLane {
+    Lines {
+        entry: Line,
+        exit: Line,
+        direction: Direction, // Which way both lines are crossed going down the pit lane
+    },
+    Area(Polygon),
+}
+
+impl PitLane {
+    pub fn from_lines(entry: ((f64, f64), (f64, f64)), exit: ((f64, f64), (f64, f64))) -> PitLane {
+        let line = |(start, end): ((f64, f64), (f64, f64))| {
+            Line::new(coord! {x: start.0, y: start.1}, coord! {x: end.0, y: end.1})
+        };
+        PitLane::Lines {
+            entry: line(entry),
+            exit: line(exit),
+            direction: Direction::Any,
+        }
+    }
+
+    // Only count going over the lines this way, so driving back over them
+    // doesn't take us in or out of the pits. The outline of the pit lane
+    // has no way round.
+    pub fn set_direction(&mut self, direction: Direction) {
+        if let PitLane::Lines { direction: d, .. } = self {
+            *d = direction;
+        }
+    }
+
+    // The outline as (lat, long) points, it gets closed for you
+    pub fn from_area(outline: &[(f64, f64)]) -> PitLane {
+        let exterior: LineString = outline
+            .iter()
+            .map(|(lat, long)| coord! {x: *lat, y: *long})
+            .collect();
+        PitLane::Area(Polygon::new(exterior, vec![]))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PitStop {
+    pub entry: SystemTime,
+    pub exit: SystemTime,
+}
+
+impl PitStop {
+    // Time from pit entry to pit exit
+    pub fn duration(&self) -> Duration {
+        self.exit.duration_since(self.entry).unwrap_or_default()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PitEvent {
+    Entered(SystemTime),
+    Exited(PitStop),
+}
+
+impl Session {
+    // Checks if the lap just went in or out of the pits. Going in makes the
+    // lap the in lap. Coming out finishes the in lap if it hasn't already
+    // been finished at start/finish, and the out lap starts at pit exit.
+    pub fn update_pits(&mut self, lap: &mut Lap) -> Option<PitEvent> {
+        let pit_lane = self.track.pit_lane.as_ref()?;
+        let point = lap.points.last()?;
+        let at = match pit_lane {
+            PitLane::Lines {
+                entry,
+                exit,
+                direction,
+            } => {
+                let line = if self.pit_entry.is_none() {
+                    entry
+                } else {
+                    exit
+                };
+                lap.crossing_time(*line, *direction)
+            }
+            PitLane::Area(area) => {
+                let inside = area.contains(&point.coord);
+                (inside == self.pit_entry.is_none()).then_some(point.time)
+            }
+        }?;
+
+        let entry = match self.pit_entry.take() {
+            None => {
+                self.pit_entry = Some(at);
+                if let LapType::Lap(_) = lap.lap_type {
+                    lap.lap_type = LapType::In;
+                }
+                return Some(PitEvent::Entered(at));
+            }
+            Some(entry) => entry,
+        };
+
+        let stop = PitStop { entry, exit: at };
+        self.pit_stops.push(stop);
+        if lap.lap_type == LapType::In && lap.points.len() >= 2 {
+            let in_lap = std::mem::replace(lap, Lap::new(LapType::Out));
+            self.laps.push(in_lap);
+            let last_lap = self.laps.len() - 1;
+            *lap = self.laps[last_lap].next_lap_at(at);
+        }
+        Some(PitEvent::Exited(stop))
+    }
+
+    // In the pit lane right now
+    pub fn in_pits(&self) -> bool {
+        self.pit_entry.is_some()
+    }
+
+    pub fn pit_stops(&self) -> &[PitStop] {
+        &self.pit_stops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Track;
+    use std::time::UNIX_EPOCH;
+
+    fn secs(s: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(s)
+    }
+
+    // Laps run north across start/finish at latitude 2.5, going back south
+    // down the other side of the track at longitude 5
+    fn track(pit_lane: PitLane) -> Track {
+        let mut track = Track::new("Test".to_string(), (2.5, 0.0), (2.5, 2.0));
+        track.set_pit_lane(pit_lane);
+        track
+    }
+
+    // Drive the points, one a second, returning the lap we finish on
+    fn drive(session: &mut Session, points: &[(f64, f64)]) -> Lap {
+        let mut lap = session.start();
+        for (i, (lat, long)) in points.iter().enumerate() {
+            lap.add_point(*lat, *long, secs(i as u64));
+            session.update_pits(&mut lap);
+            if session.is_lap_complete(&lap) {
+                lap = session.add_lap(lap);
+            }
+        }
+        lap
+    }
+
+    // Out lap, a lap, and in through the pits at the end of the second lap
+    const LAPS: [(f64, f64); 12] = [
+        (2.0, 1.0),
+        (3.0, 1.0),
+        (4.0, 3.0),
+        (2.0, 5.0),
+        (1.0, 3.0),
+        (2.2, 1.0),
+        (3.0, 1.0),
+        (4.0, 3.0),
+        (2.0, 5.0),
+        (1.0, 3.0),
+        (1.0, 1.0),
+        (1.5, 1.0),
+    ];
+
+    #[test]
+    fn test_pit_lines() {
+        // Pit entry before the final corner, pit exit before start/finish
+        let pit_lane = PitLane::from_lines(((0.5, 2.0), (1.5, 2.0)), ((0.5, 0.5), (1.5, 0.5)));
+        let mut session = Session::new(track(pit_lane));
+        let mut points = LAPS.to_vec();
+        // Through the pit lane and back out on track
+        points.extend([(1.0, 0.0), (2.0, 1.0), (3.0, 1.0), (4.0, 3.0)]);
+        let lap = drive(&mut session, &points);
+
+        // The in lap ends at pit exit, the out lap at start/finish
+        let types: Vec<LapType> = session.laps.iter().map(|lap| lap.lap_type).collect();
+        let expected = vec![LapType::Out, LapType::Lap(1), LapType::In, LapType::Out];
+        assert_eq!(types, expected);
+        assert_eq!(lap.lap_type, LapType::Lap(2));
+        assert!(!session.in_pits());
+
+        let stop = session.pit_stops()[0];
+        assert_eq!(stop.entry, secs(9) + Duration::from_millis(500));
+        assert_eq!(stop.exit, secs(11) + Duration::from_millis(500));
+        assert_eq!(stop.duration(), Duration::from_secs(2));
+        assert_eq!(session.laps[2].end_time(), Some(stop.exit));
+
+        // In and out laps don't count
+        assert_eq!(*session.best_lap().unwrap().number(), LapType::Lap(1));
+    }
+
+    #[test]
+    fn test_pit_lines_direction() {
+        let mut pit_lane = PitLane::from_lines(((0.5, 2.0), (1.5, 2.0)), ((0.5, 0.5), (1.5, 0.5)));
+        pit_lane.set_direction(Direction::LeftToRight);
+        let mut session = Session::new(track(pit_lane));
+        // Back up the pit lane over both lines, then down it the right way
+        let mut lap = drive(&mut session, &[(1.0, 0.0), (1.0, 1.0), (1.0, 3.0)]);
+        assert!(!session.in_pits());
+        lap.add_point(1.0, 1.0, secs(3));
+        assert!(matches!(
+            session.update_pits(&mut lap),
+            Some(PitEvent::Entered(_))
+        ));
+        lap.add_point(1.0, 0.0, secs(4));
+        assert!(matches!(
+            session.update_pits(&mut lap),
+            Some(PitEvent::Exited(_))
+        ));
+    }
+
+    #[test]
+    fn test_pit_area() {
+        let pit_lane = PitLane::from_area(&[(0.5, 0.5), (0.5, 1.5), (1.7, 1.5), (1.7, 0.5)]);
+        let mut session = Session::new(track(pit_lane));
+        let mut lap = drive(&mut session, &LAPS);
+        assert!(session.in_pits());
+        assert_eq!(lap.lap_type, LapType::In);
+
+        lap.add_point(1.5, 2.0, secs(20));
+        assert_eq!(
+            session.update_pits(&mut lap),
+            Some(PitEvent::Exited(PitStop {
+                entry: secs(10),
+                exit: secs(20)
+            }))
+        );
+        assert_eq!(lap.lap_type, LapType::Out);
+        assert_eq!(session.last_lap().unwrap().lap_type, LapType::In);
+    }
+
+    #[test]
+    fn test_finish() {
+        let track = Track::new("Test".to_string(), (2.5, 0.0), (2.5, 2.0));
+        let mut session = Session::new(track);
+        let lap = drive(&mut session, &LAPS[..8]);
+        session.finish(lap);
+        let last_lap = session.last_lap().unwrap();
+        assert_eq!(last_lap.lap_type, LapType::In);
+        assert_eq!(last_lap.end_time(), Some(secs(7)));
+    }
+}
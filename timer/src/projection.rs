@@ -0,0 +1,88 @@
+use geo::{coord, Coord};
+
+// WGS84 ellipsoid
+const SEMI_MAJOR_AXIS: f64 = 6_378_137.0; // Metres
+const FLATTENING: f64 = 1.0 / 298.257_223_563;
+
+// A flat east/north frame in metres around an origin, for working out lines
+// and distances on track. It's a tangent plane, so it's good to well under a
+// centimetre over a few kilometres at any latitude but not much further, so
+// anchor it close to where it's used.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Projection {
+    origin: (f64, f64), // (lat, long)
+    north: f64,         // Metres per degree of latitude at the origin
+    east: f64,          // Metres per degree of longitude at the origin
+}
+
+impl Projection {
+    pub fn new(origin: (f64, f64)) -> Projection {
+        let e2 = FLATTENING * (2.0 - FLATTENING);
+        let lat = origin.0.to_radians();
+        let w = (1.0 - e2 * lat.sin().powi(2)).sqrt();
+        // Radii of curvature along the meridian and the prime vertical
+        let meridian = SEMI_MAJOR_AXIS * (1.0 - e2) / w.powi(3);
+        let prime_vertical = SEMI_MAJOR_AXIS / w;
+        Projection {
+            origin,
+            north: meridian.to_radians(),
+            east: (prime_vertical * lat.cos()).to_radians(),
+        }
+    }
+
+    pub fn origin(&self) -> (f64, f64) {
+        self.origin
+    }
+
+    // Metres east (x) and north (y) of the origin
+    pub fn to_local(&self, lat: f64, long: f64) -> Coord {
+        coord! {
+            x: (long - self.origin.1) * self.east,
+            y: (lat - self.origin.0) * self.north,
+        }
+    }
+
+    // Back to (lat, long)
+    pub fn to_global(&self, c: Coord) -> (f64, f64) {
+        (
+            self.origin.0 + c.y / self.north,
+            self.origin.1 + c.x / self.east,
+        )
+    }
+
+    // Same as to_local for a coordinate kept the timer's way round, x as
+    // latitude and y as longitude
+    pub(crate) fn project(&self, c: Coord) -> Coord {
+        self.to_local(c.x, c.y)
+    }
+
+    pub(crate) fn unproject(&self, c: Coord) -> Coord {
+        let (lat, long) = self.to_global(c);
+        coord! {x: lat, y: long}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scale() {
+        // A degree of latitude is longer at the poles than the equator
+        let equator = Projection::new((0.0, 0.0)).to_local(1.0, 1.0);
+        assert!((equator.y - 110_574.0).abs() < 1.0);
+        assert!((equator.x - 111_320.0).abs() < 1.0);
+        // Longitude shrinks with latitude, halving by 60 degrees
+        let north = Projection::new((60.0, 0.0)).to_local(60.0, 1.0);
+        assert!((north.x - 55_800.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let projection = Projection::new((38.161, -122.455));
+        let local = projection.to_local(38.162, -122.454);
+        let (lat, long) = projection.to_global(local);
+        assert!((lat - 38.162).abs() < 1e-12);
+        assert!((long + 122.454).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,308 @@
+use geo::coord;
+use geo::geometry::Line;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+use crate::{Direction, Gate, Lap, LapType};
+
+// Point to point timing for hillclimbs, rally stages and autocross, where a
+// run starts at one line and finishes at another instead of going round.
+
+// A line the run has to go through
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Mark {
+    line: Line,
+    direction: Direction,
+    gate: Option<Gate>,
+}
+
+impl Mark {
+    fn from_points(start: (f64, f64), end: (f64, f64)) -> Mark {
+        Mark {
+            line: Line::new(coord! {x: start.0, y: start.1}, coord! {x: end.0, y: end.1}),
+            direction: Direction::Any,
+            gate: None,
+        }
+    }
+
+    fn from_gate(gate: Gate) -> Mark {
+        Mark {
+            line: gate.line(),
+            direction: Direction::LeftToRight,
+            gate: Some(gate),
+        }
+    }
+
+    // How far along the lap's last segment it went through
+    fn passed(&self, lap: &Lap) -> Option<f64> {
+        lap.passed(self.line, self.direction, self.gate.as_ref())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Stage {
+    name: String,
+    start: Mark,
+    finish: Mark,
+    splits: Vec<Mark>, // In the order they're reached
+}
+
+impl Stage {
+    // Start and finish lines each given by their two ends
+    pub fn new(
+        name: String,
+        start: ((f64, f64), (f64, f64)),
+        finish: ((f64, f64), (f64, f64)),
+    ) -> Stage {
+        Stage {
+            name,
+            start: Mark::from_points(start.0, start.1),
+            finish: Mark::from_points(finish.0, finish.1),
+            splits: Vec::new(),
+        }
+    }
+
+    pub fn from_gates(name: String, start: Gate, finish: Gate) -> Stage {
+        Stage {
+            name,
+            start: Mark::from_gate(start),
+            finish: Mark::from_gate(finish),
+            splits: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Which way the start and finish lines are crossed. Set this if the way
+    // back from the finish goes over the lines, so driving back doesn't
+    // start a run.
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.start.direction = direction;
+        self.finish.direction = direction;
+        for split in &mut self.splits {
+            split.direction = direction;
+        }
+    }
+
+    pub fn add_split(&mut self, split_start: (f64, f64), split_end: (f64, f64)) {
+        let mut split = Mark::from_points(split_start, split_end);
+        split.direction = self.finish.direction;
+        self.splits.push(split);
+    }
+
+    pub fn add_split_gate(&mut self, gate: Gate) {
+        self.splits.push(Mark::from_gate(gate));
+    }
+
+    // Number of sector times in a run, 0 if the stage has no splits
+    pub fn sector_count(&self) -> usize {
+        match self.splits.len() {
+            0 => 0,
+            splits => splits + 1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RunEvent {
+    Started(u16),            // Run number
+    Split(u16, Duration),    // Run number and the sector time
+    Finished(u16, Duration), // Run number and the run time
+}
+
+// Times runs on a stage one after another. After the finish it goes back to
+// waiting for the next start on its own.
+pub struct StageTimer {
+    stage: Stage,
+    runs: Vec<Lap>,       // Finished runs, numbered as laps
+    current: Option<Lap>, // The run in progress
+    waiting: Lap,         // Where we've been since the last run
+}
+
+impl StageTimer {
+    pub fn new(stage: Stage) -> StageTimer {
+        StageTimer {
+            stage,
+            runs: Vec::new(),
+            current: None,
+            waiting: Lap::new(LapType::Out),
+        }
+    }
+
+    // Add a telemetry point, at is when the sample was taken
+    pub fn add_point(&mut self, lat: f64, long: f64, at: SystemTime) -> Option<RunEvent> {
+        let run = match self.current.as_mut() {
+            Some(run) => run,
+            None => return self.wait(lat, long, at),
+        };
+        run.add_point(lat, long, at);
+        let number = match run.lap_type {
+            LapType::Lap(number) => number,
+            _ => 0,
+        };
+
+        if let Some(split) = self.stage.splits.get(run.current_sector()) {
+            if let Some(fraction) = split.passed(run) {
+                let time = run.split_at(run.time_at(fraction));
+                return Some(RunEvent::Split(number, time));
+            }
+        }
+
+        let fraction = self.stage.finish.passed(run)?;
+        let end = run.time_at(fraction);
+        let mut waiting = run.next_lap_at(end);
+        waiting.lap_type = LapType::Out;
+        // The last sector finishes at the finish, if we went through every split
+        if !self.stage.splits.is_empty() && run.sectors.len() == self.stage.splits.len() {
+            run.split_at(end);
+        }
+        let time = run.duration();
+        self.runs.extend(self.current.take());
+        self.waiting = waiting;
+        Some(RunEvent::Finished(number, time))
+    }
+
+    fn wait(&mut self, lat: f64, long: f64, at: SystemTime) -> Option<RunEvent> {
+        self.waiting.add_point(lat, long, at);
+        let fraction = match self.stage.start.passed(&self.waiting) {
+            Some(fraction) => fraction,
+            None => {
+                // Only the last point is needed to spot the start
+                let len = self.waiting.points.len();
+                self.waiting.points.drain(..len - 1);
+                return None;
+            }
+        };
+        let start = self.waiting.time_at(fraction);
+        let number = self.runs.len() as u16 + 1;
+        let mut run = self.waiting.next_lap_at(start);
+        run.lap_type = LapType::Lap(number);
+        self.waiting = Lap::new(LapType::Out);
+        self.current = Some(run);
+        Some(RunEvent::Started(number))
+    }
+
+    // Abandon the run in progress, e.g. after a false start or a red flag
+    pub fn reset(&mut self) {
+        self.current = None;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn current_run(&self) -> Option<&Lap> {
+        self.current.as_ref()
+    }
+
+    pub fn runs(&self) -> &[Lap] {
+        &self.runs
+    }
+
+    // Quickest valid run, the first one set if there's a tie
+    pub fn best_run(&self) -> Option<&Lap> {
+        self.runs
+            .iter()
+            .filter(|run| run.is_valid())
+            .min_by_key(|run| run.duration())
+    }
+
+    pub fn stage(&self) -> &Stage {
+        &self.stage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Projection;
+    use std::time::UNIX_EPOCH;
+
+    fn secs(s: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(s)
+    }
+
+    // Up the hill from latitude 1.5 to 4.5 with a split at 2.5, only
+    // counting going up
+    fn stage() -> Stage {
+        let mut stage = Stage::new(
+            "Hill".to_string(),
+            ((1.5, 0.0), (1.5, 10.0)),
+            ((4.5, 0.0), (4.5, 10.0)),
+        );
+        stage.add_split((2.5, 0.0), (2.5, 10.0));
+        stage.set_direction(Direction::LeftToRight);
+        stage
+    }
+
+    fn drive(timer: &mut StageTimer, lats: &[f64]) -> Vec<(usize, RunEvent)> {
+        lats.iter()
+            .enumerate()
+            .filter_map(|(i, lat)| timer.add_point(*lat, 1.0, secs(i as u64)).map(|e| (i, e)))
+            .collect()
+    }
+
+    #[test]
+    fn test_runs() {
+        let mut timer = StageTimer::new(stage());
+        // Up, back down again, and up for a second run
+        let lats = [
+            1.0, 2.0, 3.0, 4.0, 5.0, 4.0, 3.0, 2.0, 1.0, 2.0, 3.0, 4.0, 5.0,
+        ];
+        let events = drive(&mut timer, &lats);
+        let second = Duration::from_secs(1);
+        assert_eq!(
+            events,
+            vec![
+                (1, RunEvent::Started(1)),
+                (2, RunEvent::Split(1, second)),
+                (4, RunEvent::Finished(1, 3 * second)),
+                (9, RunEvent::Started(2)),
+                (10, RunEvent::Split(2, second)),
+                (12, RunEvent::Finished(2, 3 * second)),
+            ]
+        );
+        assert!(!timer.is_running());
+        assert_eq!(timer.runs().len(), 2);
+        let run = &timer.runs()[0];
+        assert_eq!(*run.number(), LapType::Lap(1));
+        assert_eq!(run.sectors(), &[second, 2 * second]);
+        assert_eq!(run.start_time(), Some(secs(0) + second / 2));
+        assert_eq!(*timer.best_run().unwrap().number(), LapType::Lap(1));
+    }
+
+    #[test]
+    fn test_gate_diagonal() {
+        // Heading north east through a 10m start gate, the segment before
+        // the start passes close to the centre but doesn't start the run
+        let start = Gate::new((38.0, -122.0), 0.0, 10.0);
+        let finish = Gate::new((38.001, -122.0), 0.0, 10.0);
+        let mut timer = StageTimer::new(Stage::from_gates("Hill".to_string(), start, finish));
+        let projection = Projection::new((38.0, -122.0));
+        let mut events = Vec::new();
+        for (i, (east, north)) in [(-5.0, -9.0), (1.0, -3.0), (3.5, -0.5), (6.0, 2.0)]
+            .iter()
+            .enumerate()
+        {
+            let (lat, long) = projection.to_global(coord! {x: *east, y: *north});
+            if let Some(event) = timer.add_point(lat, long, secs(i as u64)) {
+                events.push((i, event));
+            }
+        }
+        assert_eq!(events, vec![(3, RunEvent::Started(1))]);
+        assert!(timer.current_run().unwrap().start_time().unwrap() > secs(2));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut timer = StageTimer::new(stage());
+        drive(&mut timer, &[1.0, 2.0, 3.0]);
+        assert!(timer.is_running());
+        timer.reset();
+        assert!(!timer.is_running());
+        assert!(timer.runs().is_empty());
+        assert_eq!(timer.stage().sector_count(), 2);
+    }
+}
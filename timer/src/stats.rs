@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{Lap, LapMetrics, LapType, Session};
+
+// One row of the lap table
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LapSummary {
+    pub lap_type: LapType,
+    pub lap_time: Duration,
+    pub sectors: Vec<Duration>,
+    pub valid: bool,
+    pub best: bool, // Quickest valid timed lap of the session
+    pub metrics: LapMetrics,
+}
+
+impl Session {
+    // Completed timed laps that count, out and in laps and invalid laps don't
+    fn timed_laps(&self) -> impl Iterator<Item = &Lap> {
+        self.laps
+            .iter()
+            .filter(|lap| matches!(lap.lap_type, LapType::Lap(_)) && lap.is_valid())
+    }
+
+    fn lap_times(&self) -> Vec<Duration> {
+        self.timed_laps().map(|lap| lap.duration()).collect()
+    }
+
+    // Quickest valid timed lap, the first one set if there's a tie
+    pub fn best_lap(&self) -> Option<&Lap> {
+        self.timed_laps().min_by_key(|lap| lap.duration())
+    }
+
+    // The best sectors added together, None until every sector has a time
+    pub fn theoretical_best(&self) -> Option<Duration> {
+        if self.best_sectors.is_empty() {
+            return None;
+        }
+        self.best_sectors.iter().copied().sum()
+    }
+
+    pub fn average_lap(&self) -> Option<Duration> {
+        let times = self.lap_times();
+        if times.is_empty() {
+            return None;
+        }
+        Some(times.iter().sum::<Duration>() / times.len() as u32)
+    }
+
+    pub fn median_lap(&self) -> Option<Duration> {
+        let mut times = self.lap_times();
+        if times.is_empty() {
+            return None;
+        }
+        times.sort();
+        let middle = times.len() / 2;
+        if times.len().is_multiple_of(2) {
+            Some((times[middle - 1] + times[middle]) / 2)
+        } else {
+            Some(times[middle])
+        }
+    }
+
+    // Standard deviation of the lap times, lower is more consistent. Needs at
+    // least two laps.
+    pub fn consistency(&self) -> Option<Duration> {
+        let times: Vec<f64> = self.lap_times().iter().map(|t| t.as_secs_f64()).collect();
+        if times.len() < 2 {
+            return None;
+        }
+        let mean = times.iter().sum::<f64>() / times.len() as f64;
+        let variance =
+            times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / (times.len() - 1) as f64;
+        Some(Duration::from_secs_f64(variance.sqrt()))
+    }
+
+    // Every completed lap in order, including out and in laps
+    pub fn lap_table(&self) -> Vec<LapSummary> {
+        let best = self.best_lap().map(|lap| lap.lap_type);
+        self.laps
+            .iter()
+            .map(|lap| LapSummary {
+                lap_type: lap.lap_type,
+                lap_time: lap.duration(),
+                sectors: lap.sectors.clone(),
+                valid: lap.is_valid(),
+                best: best == Some(lap.lap_type),
+                metrics: lap.metrics(),
+            })
+            .collect()
+    }
+
+    // Mark a completed lap as not counting, e.g. for cutting the track
+    pub fn set_lap_valid(&mut self, lap_type: LapType, valid: bool) {
+        if let Some(lap) = self.laps.iter_mut().find(|lap| lap.lap_type == lap_type) {
+            lap.set_valid(valid);
+        }
+        if !self.pinned {
+            self.reference = self.best_reference();
+        }
+        self.best_sectors = self.best_sectors_of_timed_laps();
+    }
+
+    // Best sectors worked out again from the laps that count. The lap in
+    // progress adds its sectors back when it's finished.
+    fn best_sectors_of_timed_laps(&self) -> Vec<Option<Duration>> {
+        let mut best = vec![None; self.best_sectors.len()];
+        for lap in self.timed_laps() {
+            for (best, sector) in best.iter_mut().zip(lap.sectors()) {
+                if best.is_none_or(|b| *sector < b) {
+                    *best = Some(*sector);
+                }
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Track;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn secs(s: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(s)
+    }
+
+    fn session() -> Session {
+        Session::new(Track::new("Test".to_string(), (2.5, 0.0), (2.5, 10.0)))
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut session = session();
+        assert!(session.best_lap().is_none());
+        assert!(session.average_lap().is_none());
+        assert!(session.consistency().is_none());
+
+        // Laps of 90, 94, 92 and 100 seconds
+        let times = [0, 90, 184, 276, 376];
+        let mut lap = session.start();
+        for (i, t) in times.iter().enumerate() {
+            let lat = if i % 2 == 0 { 2.0 } else { 3.0 };
+            lap.add_point(lat, 1.0, secs(*t));
+            lap.add_point(5.0 - lat, 1.0, secs(*t));
+            assert!(session.is_lap_complete(&lap));
+            lap = session.add_lap(lap);
+        }
+        let lap_times: Vec<u64> = session
+            .lap_table()
+            .iter()
+            .map(|lap| lap.lap_time.as_secs())
+            .collect();
+        assert_eq!(lap_times, vec![0, 90, 94, 92, 100]);
+
+        assert_eq!(*session.best_lap().unwrap().number(), LapType::Lap(1));
+        assert_eq!(session.average_lap(), Some(Duration::from_secs(94)));
+        assert_eq!(session.median_lap(), Some(Duration::from_secs(93)));
+        let consistency = session.consistency().unwrap().as_secs_f64();
+        assert!((consistency - 4.320).abs() < 0.001);
+
+        // Invalid laps don't count towards anything
+        session.set_lap_valid(LapType::Lap(1), false);
+        assert_eq!(*session.best_lap().unwrap().number(), LapType::Lap(3));
+        assert_eq!(session.median_lap(), Some(Duration::from_secs(94)));
+        let table = session.lap_table();
+        assert!(!table[1].valid);
+        assert!(table[3].best);
+        assert_eq!(table.iter().filter(|lap| lap.best).count(), 1);
+    }
+
+    #[test]
+    fn test_theoretical_best() {
+        // Without splits there's nothing to add up
+        assert!(session().theoretical_best().is_none());
+
+        let mut session = session();
+        session.best_sectors = vec![Some(Duration::from_secs(30)), None];
+        assert!(session.theoretical_best().is_none());
+        session.best_sectors[1] = Some(Duration::from_secs(40));
+        assert_eq!(session.theoretical_best(), Some(Duration::from_secs(70)));
+    }
+
+    #[test]
+    fn test_invalid_lap_best_sectors() {
+        let mut session = session();
+        session.best_sectors = vec![None, None];
+        for (number, sectors) in [(1, [30, 45]), (2, [35, 40])] {
+            let mut lap = Lap::new(LapType::Lap(number));
+            for sector in sectors {
+                let sector = Duration::from_secs(sector);
+                lap.sectors.push(sector);
+                session.record_sector(lap.lap_type, lap.sectors.len() - 1, sector);
+            }
+            session.laps.push(lap);
+        }
+        assert_eq!(session.theoretical_best(), Some(Duration::from_secs(70)));
+
+        // Lap 1 had the best first sector
+        session.set_lap_valid(LapType::Lap(1), false);
+        assert_eq!(
+            session.best_sectors(),
+            &[Some(Duration::from_secs(35)), Some(Duration::from_secs(40))]
+        );
+        session.set_lap_valid(LapType::Lap(1), true);
+        assert_eq!(session.theoretical_best(), Some(Duration::from_secs(70)));
+    }
+}
@@ -31,10 +31,11 @@ fn feature_to_sfline(f: Feature) -> Track {
             x
         }
     };
+    // GeoJSON positions are [long, lat]
     Track {
         name: track.to_string(),
-        sf_start: (ls[0][0], ls[0][1]),
-        sf_end: (ls[1][0], ls[1][1]),
+        sf_start: (ls[0][1], ls[0][0]),
+        sf_end: (ls[1][1], ls[1][0]),
     }
 }
 
@@ -51,6 +52,9 @@ fn get_db(filename: &Path) -> Connection {
     match Connection::open(filename) {
         Err(e) => panic!("Failed to open database: {}", e),
         Ok(c) => {
+            if let Err(e) = track::migrate(&c) {
+                panic!("Failed to migrate database: {}", e)
+            };
             if let Err(e) = c.execute(
                 "CREATE TABLE IF NOT EXISTS tracks (
                     id INTEGER PRIMARY KEY,
@@ -1,15 +1,44 @@
+use geo::HaversineDistance;
 use rusqlite::{named_params, Connection, Result};
 use serde::Deserialize;
 use serde::Serialize;
 use std::error::Error;
 use std::path::Path;
 
+// tracks.db files from before GeoJSON positions were read as [long, lat]
+// have every start/finish line stored (long, lat). user_version is 1 once
+// they're (lat, long), a new database starts off that way.
+pub fn migrate(conn: &Connection) -> Result<()> {
+    let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > 0 {
+        return Ok(());
+    }
+    let exists: bool = conn.query_row(
+        "SELECT count(*) > 0 FROM sqlite_master WHERE type='table' AND name='tracks'",
+        [],
+        |row| row.get(0),
+    )?;
+    let tx = conn.unchecked_transaction()?;
+    if exists {
+        tx.execute(
+            "UPDATE tracks SET value=json_set(value,
+                '$.sf_start', json_array(value->'$.sf_start[1]', value->'$.sf_start[0]'),
+                '$.sf_end', json_array(value->'$.sf_end[1]', value->'$.sf_end[0]'))",
+            [],
+        )?;
+    }
+    tx.execute_batch("PRAGMA user_version = 1")?;
+    tx.commit()
+}
+
+// Nothing picks a track from the database yet, only the tests use this
+#[allow(dead_code)]
 struct Tracks {
-    filename: &'static Path,
     conn: Connection,
     tracks: Vec<Track>,
 }
 
+#[allow(dead_code)]
 impl Tracks {
     fn new(filename: &'static Path) -> Self {
         // Open or create the tracks DB
@@ -17,6 +46,9 @@ impl Tracks {
             Err(e) => panic!("Failed to open database: {}", e),
             Ok(c) => c,
         };
+        if let Err(e) = migrate(&conn) {
+            panic!("Failed to migrate database: {}", e)
+        };
         if let Err(e) = conn.execute(
             "CREATE TABLE IF NOT EXISTS tracks (
                 id INTEGER PRIMARY KEY,
@@ -28,7 +60,7 @@ impl Tracks {
         };
 
         let mut stmt = conn.prepare("SELECT value FROM tracks").unwrap();
-        let values = stmt.query_map([], |row| Ok(row.get(0)?)).unwrap();
+        let values = stmt.query_map([], |row| row.get(0)).unwrap();
         let mut tracks: Vec<Track> = Vec::new();
         for value in values {
             tracks.push(serde_json::from_value(value.unwrap()).unwrap());
@@ -40,11 +72,7 @@ impl Tracks {
             Ok(c) => c,
         };
 
-        Tracks {
-            filename,
-            conn,
-            tracks,
-        }
+        Tracks { conn, tracks }
     }
 
     fn add(
@@ -71,11 +99,14 @@ impl Tracks {
     // Finds the track with the start/finish line closest to the coordinate
     // We're using option because the DB may be empty
     fn find_nearest(&self, lat: f64, long: f64) -> Option<Track> {
+        let here = geo::Point::new(long, lat);
         let mut nearest: Option<Track> = None;
         let mut distance = f64::MAX;
         for track in self.tracks.iter() {
-            let d =
-                ((lat - track.sf_start.0).powf(2.0) + (long - track.sf_start.1).powf(2.0)).sqrt();
+            // Metres over the surface of the earth, degrees of longitude
+            // shrink away from the equator
+            let (sf_lat, sf_long) = track.sf_start;
+            let d = here.haversine_distance(&geo::Point::new(sf_long, sf_lat));
             if nearest.is_none() || d < distance {
                 nearest = Some(track.clone());
                 distance = d;
@@ -88,12 +119,12 @@ impl Tracks {
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Track {
     pub name: String,
-    pub sf_start: (f64, f64), // Start of the start/finish line
-    pub sf_end: (f64, f64),   // End of the start/finish line
+    pub sf_start: (f64, f64), // Start of the start/finish line, (lat, long)
+    pub sf_end: (f64, f64),   // End of the start/finish line, (lat, long)
 }
 
 impl Track {
-    fn new(name: String, sf_start: (f64, f64), sf_end: (f64, f64)) -> Self {
+    pub fn new(name: String, sf_start: (f64, f64), sf_end: (f64, f64)) -> Self {
         Track {
             name,
             sf_start,
@@ -114,7 +145,7 @@ mod tests {
     #[test]
     fn test_tracks() {
         // Clean up any previous tests
-        remove_file("tracks_rust_test.db");
+        let _ = remove_file("tracks_rust_test.db");
 
         let filename = Path::new("tracks_rust_test.db");
         let mut tracks = Tracks::new(filename);
@@ -139,8 +170,42 @@ mod tests {
         let t = tracks.find_nearest(8.0, 8.0).unwrap();
         assert_eq!(t.name, "Just a bit closer");
 
+        // A degree of longitude up north is shorter than a degree of latitude
+        let _ = tracks
+            .add("North".to_string(), (61.0, 10.0), (61.0, 10.1))
+            .unwrap();
+        let _ = tracks
+            .add("East".to_string(), (60.0, 11.1), (60.0, 11.2))
+            .unwrap();
+        let t = tracks.find_nearest(60.0, 10.0).unwrap();
+        assert_eq!(t.name, "East");
+
         // Clean up this test
-        remove_file("tracks_rust_test.db");
+        let _ = remove_file("tracks_rust_test.db");
+    }
+
+    #[test]
+    fn test_migrate() {
+        // Written by the old converter, the line is (long, lat)
+        let _ = remove_file("tracks_rust_test_migrate.db");
+        let filename = Path::new("tracks_rust_test_migrate.db");
+        let conn = Connection::open(filename).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE tracks (id INTEGER PRIMARY KEY, value TEXT NOT NULL);
+             INSERT INTO tracks (value) VALUES
+                ('{\"name\":\"Old\",\"sf_start\":[-122.1,38.1],\"sf_end\":[-122.2,38.2]}');",
+        )
+        .unwrap();
+        drop(conn);
+
+        // Only put right the once
+        for _ in 0..2 {
+            let tracks = Tracks::new(filename);
+            let t = tracks.find_nearest(38.1, -122.1).unwrap();
+            assert_eq!(t.sf_start, (38.1, -122.1));
+            assert_eq!(t.sf_end, (38.2, -122.2));
+        }
+        let _ = remove_file("tracks_rust_test_migrate.db");
     }
 
     #[test]